@@ -0,0 +1,67 @@
+// Drawing a background every frame: decoding the BMP each time, the way it
+// used to be done, against blitting an already decoded framebuffer. Both go
+// through the real Canvas, so on a Pi with the panel attached the numbers
+// include setting the LEDs, elsewhere only the preview mirror.
+//
+//     cargo bench --bench backgrounds
+
+// leddy is a binary, so the modules involved are pulled in from src directly
+#![allow(dead_code)]
+
+#[path = "../src/error.rs"]
+mod error;
+#[path = "../src/preview.rs"]
+mod preview;
+
+mod screens {
+    #[path = "../../src/screens/canvas.rs"]
+    mod canvas;
+    #[path = "../../src/screens/framebuffer.rs"]
+    mod framebuffer;
+
+    pub use canvas::{Canvas, HEIGHT, WIDTH};
+    pub use framebuffer::Framebuffer;
+}
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use embedded_graphics::{image::Image, pixelcolor::Rgb888, prelude::*};
+use image::ImageFormat;
+use rpi_led_matrix::{LedMatrix, LedMatrixOptions};
+use tinybmp::DynamicBmp;
+
+use preview::Frame;
+use screens::{Canvas, Framebuffer, HEIGHT, WIDTH};
+
+static BACKGROUND: &[u8] = include_bytes!("../sakura-bg.bmp");
+
+fn matrix() -> Option<LedMatrix> {
+    let mut options = LedMatrixOptions::new();
+    options.set_hardware_mapping("adafruit-hat-pwm");
+    options.set_rows(32);
+    options.set_cols(64);
+    LedMatrix::new(Some(options), None).ok()
+}
+
+fn backgrounds(c: &mut Criterion) {
+    let mut canvas = Canvas::new(matrix(), Frame::shared(WIDTH, HEIGHT));
+
+    c.bench_function("decode bmp every frame", |b| {
+        b.iter(|| {
+            let bmp = DynamicBmp::<Rgb888>::from_slice(black_box(BACKGROUND)).unwrap();
+            Image::new(&bmp, Point::zero()).draw(&mut canvas).unwrap();
+        })
+    });
+
+    // Paid once per image, when it is downloaded
+    c.bench_function("decode into a framebuffer", |b| {
+        b.iter(|| Framebuffer::from_bytes(black_box(BACKGROUND), ImageFormat::Bmp).unwrap())
+    });
+
+    let frame = Framebuffer::from_bytes(BACKGROUND, ImageFormat::Bmp).unwrap();
+    c.bench_function("blit decoded framebuffer", |b| {
+        b.iter(|| canvas.blit(black_box(&frame)))
+    });
+}
+
+criterion_group!(benches, backgrounds);
+criterion_main!(benches);
@@ -0,0 +1,180 @@
+use chrono::{Local, NaiveTime, Utc};
+use embedded_graphics::prelude::*;
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_6X10, MonoTextStyle},
+    pixelcolor::Rgb888,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Alignment, Text},
+};
+use std::time::{Duration, Instant};
+
+use crate::config::AlertsConfig;
+use crate::error::Result;
+use crate::history::{SharedHistory, STALE_AFTER};
+use crate::screens::{border, Canvas};
+use crate::sensors::Metric;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// One full pulse of the border
+const PULSE_PERIOD: f32 = 1.5;
+
+// Border colors per level, the last one is reused if there are more thresholds
+const COLORS: [Rgb888; 2] = [Rgb888::new(255, 140, 0), Rgb888::new(255, 0, 0)];
+
+// Watches CO2 and raises an alert whenever it crosses one of the thresholds.
+// A level is only left once CO2 is `hysteresis` below its threshold, so a
+// reading hovering around it does not make the alert flicker.
+pub struct Alerts {
+    config: AlertsConfig,
+    quiet: Option<(NaiveTime, NaiveTime)>,
+    history: SharedHistory,
+    level: usize,
+    acknowledged: usize,
+    co2: f32,
+    checked: Option<Instant>,
+    started: Instant,
+}
+
+impl Alerts {
+    pub fn new(config: &AlertsConfig, history: SharedHistory) -> Self {
+        Alerts {
+            config: config.clone(),
+            quiet: config.quiet_start.zip(config.quiet_end),
+            history,
+            level: 0,
+            acknowledged: 0,
+            co2: 0.0,
+            checked: None,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn update(&mut self) {
+        if !self.config.enabled || matches!(self.checked, Some(at) if at.elapsed() < CHECK_INTERVAL) {
+            return;
+        }
+        self.checked = Some(Instant::now());
+
+        let co2 = match self.history.lock().expect("lock history").latest(Metric::Co2) {
+            Some(latest) => latest,
+            None => return,
+        };
+
+        // A sensor that died while CO2 was high should not keep the alert up
+        let age = (Utc::now() - co2.start).to_std().unwrap_or_default();
+        if age > STALE_AFTER {
+            self.level = 0;
+            self.acknowledged = 0;
+            return;
+        }
+
+        let co2 = co2.mean;
+        self.co2 = co2;
+
+        let thresholds = &self.config.thresholds;
+        while self.level < thresholds.len() && co2 >= thresholds[self.level] {
+            self.level += 1;
+        }
+        while self.level > 0 && co2 < thresholds[self.level - 1] - self.config.hysteresis {
+            self.level -= 1;
+        }
+
+        // Going back down re-arms the levels above
+        self.acknowledged = self.acknowledged.min(self.level);
+    }
+
+    pub fn active(&self) -> bool {
+        self.level > self.acknowledged && !self.is_quiet()
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.acknowledged = self.level;
+    }
+
+    fn is_quiet(&self) -> bool {
+        let (start, end) = match self.quiet {
+            Some(quiet) => quiet,
+            None => return false,
+        };
+
+        let now = Local::now().time();
+        if start <= end {
+            start <= now && now < end
+        } else {
+            // Wraps around midnight, e.g. 22:00 - 07:00
+            now >= start || now < end
+        }
+    }
+
+    pub fn draw(&self, canvas: &mut Canvas) -> Result<()> {
+        let color = COLORS[(self.level - 1).min(COLORS.len() - 1)];
+
+        let phase = self.started.elapsed().as_secs_f32() / PULSE_PERIOD * std::f32::consts::TAU;
+        let intensity = 0.55 + 0.45 * phase.sin();
+        let pulsed = Rgb888::new(
+            (color.r() as f32 * intensity) as u8,
+            (color.g() as f32 * intensity) as u8,
+            (color.b() as f32 * intensity) as u8,
+        );
+
+        Rectangle::new(Point::new(2, 2), Size::new(60, 28))
+            .into_styled(PrimitiveStyle::with_fill(Rgb888::BLACK))
+            .draw(canvas)?;
+        border(pulsed, 2).draw(canvas)?;
+
+        let style = MonoTextStyle::new(&FONT_6X10, color);
+        Text::with_alignment("OPEN\nWINDOW", Point::new(32, 13), style, Alignment::Center)
+            .draw(canvas)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::History;
+    use std::sync::Arc;
+
+    fn alerts_at(co2: f32, age: i64) -> Alerts {
+        let history = History::shared();
+        history
+            .lock()
+            .unwrap()
+            .add(Metric::Co2, Utc::now() - chrono::Duration::seconds(age), co2);
+
+        let mut alerts = Alerts::new(&AlertsConfig::default(), Arc::clone(&history));
+        alerts.update();
+        alerts
+    }
+
+    #[test]
+    fn raised_by_a_current_reading() {
+        assert!(alerts_at(1200.0, 0).active());
+        assert!(!alerts_at(800.0, 0).active());
+    }
+
+    #[test]
+    fn stale_readings_are_ignored() {
+        assert!(!alerts_at(1200.0, 60).active());
+    }
+
+    #[test]
+    fn cleared_once_the_reading_goes_stale() {
+        let history = History::shared();
+        history.lock().unwrap().add(Metric::Co2, Utc::now(), 1200.0);
+        let mut alerts = Alerts::new(&AlertsConfig::default(), Arc::clone(&history));
+        alerts.update();
+        assert!(alerts.active());
+
+        // The sensor stopped answering a minute ago
+        *history.lock().unwrap() = History::new();
+        history
+            .lock()
+            .unwrap()
+            .add(Metric::Co2, Utc::now() - chrono::Duration::seconds(60), 1200.0);
+        alerts.checked = None;
+        alerts.update();
+        assert!(!alerts.active());
+    }
+}
@@ -0,0 +1,360 @@
+use chrono::Utc;
+use log::{error, warn};
+use serde_json::{json, Value};
+use std::io::{Cursor, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::commands::Command;
+use crate::config::ApiConfig;
+use crate::history::SharedHistory;
+use crate::preview::{self, SharedFrame};
+use crate::sensors::Metric;
+use crate::shutdown::Workers;
+
+// Request bodies are a number or a short message, anything bigger is a mistake
+const MAX_BODY: u64 = 4096;
+
+pub type SharedDisplayState = Arc<Mutex<DisplayState>>;
+
+// What the main loop is showing, kept up to date for the API
+#[derive(Debug, Clone)]
+pub struct DisplayState {
+    pub screen: &'static str,
+    pub brightness: u8,
+    pub on: bool,
+}
+
+impl DisplayState {
+    pub fn shared() -> SharedDisplayState {
+        Arc::new(Mutex::new(DisplayState {
+            screen: "",
+            brightness: 100,
+            on: true,
+        }))
+    }
+}
+
+type JsonResponse = Response<Cursor<Vec<u8>>>;
+
+struct Api {
+    token: Option<String>,
+    commands: Sender<Command>,
+    display: SharedDisplayState,
+    history: SharedHistory,
+    frame: SharedFrame,
+    started: Instant,
+}
+
+pub fn spawn(
+    config: &ApiConfig,
+    commands: Sender<Command>,
+    display: SharedDisplayState,
+    history: SharedHistory,
+    frame: SharedFrame,
+    workers: Workers,
+    cancel: Arc<AtomicBool>,
+) -> Option<JoinHandle<()>> {
+    let server = match Server::http(&config.listen) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("API disabled, cannot listen on {}: {}", config.listen, e);
+            return None;
+        }
+    };
+
+    let api = Api {
+        token: config.token.clone(),
+        commands,
+        display,
+        history,
+        frame,
+        started: Instant::now(),
+    };
+
+    Some(thread::spawn(move || {
+        while !cancel.load(Ordering::Relaxed) {
+            let mut request = match server.recv_timeout(Duration::from_millis(500)) {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("API server error: {}", e);
+                    continue;
+                }
+            };
+
+            // The live preview takes over the connection, so it cannot go through `handle`
+            if request.url().split('?').next() == Some("/preview") && api.authorized(&request) {
+                let handle = preview::stream(request, Arc::clone(&api.frame), Arc::clone(&cancel));
+                if let Some(handle) = handle {
+                    workers.add("preview stream", handle);
+                }
+                continue;
+            }
+
+            let response = api.handle(&mut request);
+            if let Err(e) = request.respond(response) {
+                warn!("API response error: {}", e);
+            }
+        }
+    }))
+}
+
+impl Api {
+    fn handle(&self, request: &mut Request) -> JsonResponse {
+        if request.method() == &Method::Get && matches!(request.url().split('?').next(), Some("/")) {
+            return preview::page();
+        }
+
+        if !self.authorized(request) {
+            return error(401, "missing or wrong bearer token");
+        }
+
+        let method = request.method().clone();
+        let url = request.url().split('?').next().unwrap_or_default().to_string();
+        let path: Vec<&str> = url.split('/').filter(|s| !s.is_empty()).collect();
+
+        match (method, path.as_slice()) {
+            (Method::Get, ["status"]) => {
+                json_response(200, status(&self.display, &self.history, self.started))
+            }
+            (Method::Post, ["screen", name]) => self.send("screen", name),
+            (Method::Post, ["input", input]) => self.send("input", input),
+            (Method::Post, ["display", on_off]) => self.send("display", on_off),
+            (Method::Post, [name @ ("brightness" | "message")]) => match body(request) {
+                Ok(body) => self.send(name, &body),
+                Err(e) => error(400, &e),
+            },
+            (_, ["status" | "screen" | "input" | "display" | "brightness" | "message", ..]) => {
+                error(405, "method not allowed")
+            }
+            _ => error(404, "not found"),
+        }
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let token = match &self.token {
+            Some(token) => token,
+            None => return true,
+        };
+
+        // Browsers cannot set headers on a WebSocket, so the preview page passes it in the query
+        let in_query = request
+            .url()
+            .split_once('?')
+            .map(|(_, query)| query.split('&').any(|p| p.strip_prefix("token=") == Some(token.as_str())))
+            .unwrap_or(false);
+
+        let expected = format!("Bearer {}", token);
+        in_query
+            || request
+                .headers()
+                .iter()
+                .any(|h| h.field.equiv("Authorization") && h.value.as_str() == expected)
+    }
+
+    // Hands a command to the main loop, which picks it up on the next frame
+    fn send(&self, name: &str, argument: &str) -> JsonResponse {
+        match Command::parse(name, argument) {
+            Ok(command) => match self.commands.send(command) {
+                Ok(()) => json_response(202, json!({ "ok": true })),
+                Err(_) => error(503, "display is shutting down"),
+            },
+            Err(e) => error(400, &e),
+        }
+    }
+}
+
+// What is on the display and the latest readings, also used by the control socket
+pub fn status(display: &SharedDisplayState, history: &SharedHistory, started: Instant) -> Value {
+    let display = display.lock().expect("lock display state").clone();
+
+    let now = Utc::now();
+    let history = history.lock().expect("lock history");
+    let mut readings = serde_json::Map::new();
+    for metric in Metric::ALL {
+        if let Some(latest) = history.latest(metric) {
+            readings.insert(
+                metric.name().to_string(),
+                json!({
+                    "value": latest.mean,
+                    "unit": metric.unit(),
+                    "age": (now - latest.start).num_seconds(),
+                }),
+            );
+        }
+    }
+
+    let on_off = if display.on { "on" } else { "off" };
+    json!({
+        "screen": display.screen,
+        "brightness": display.brightness,
+        "display": on_off,
+        "uptime": started.elapsed().as_secs(),
+        "readings": readings,
+    })
+}
+
+fn body(request: &mut Request) -> Result<String, String> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY)
+        .read_to_string(&mut body)
+        .map_err(|e| format!("cannot read body: {}", e))?;
+    Ok(body)
+}
+
+fn json_response(status: u16, body: Value) -> JsonResponse {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .expect("valid header");
+    Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type)
+}
+
+fn error(status: u16, message: &str) -> JsonResponse {
+    json_response(status, json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::History;
+    use crate::preview::Frame;
+    use crate::rotary::InputEvent;
+    use std::sync::mpsc::{channel, Receiver};
+
+    // Runs the API on a free port, everything but the preview stream
+    fn serve(token: Option<&str>) -> (String, Receiver<Command>) {
+        let server = Server::http("127.0.0.1:0").expect("listen");
+        let port = server.server_addr().to_ip().expect("an IP address").port();
+        let (tx, rx) = channel();
+
+        let api = Api {
+            token: token.map(str::to_string),
+            commands: tx,
+            display: DisplayState::shared(),
+            history: History::shared(),
+            frame: Frame::shared(64, 32),
+            started: Instant::now(),
+        };
+
+        thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let response = api.handle(&mut request);
+                request.respond(response).ok();
+            }
+        });
+
+        (format!("http://127.0.0.1:{}", port), rx)
+    }
+
+    fn status_code(request: minreq::Request) -> i32 {
+        request.send().expect("a response").status_code
+    }
+
+    #[test]
+    fn routing() {
+        let (url, _rx) = serve(None);
+
+        assert_eq!(status_code(minreq::get(format!("{}/", url))), 200);
+        assert_eq!(status_code(minreq::get(format!("{}/status", url))), 200);
+        assert_eq!(status_code(minreq::get(format!("{}/nothing", url))), 404);
+        assert_eq!(status_code(minreq::post(format!("{}/screens/graph", url))), 404);
+
+        // Known paths, wrong method
+        assert_eq!(status_code(minreq::post(format!("{}/status", url))), 405);
+        assert_eq!(status_code(minreq::get(format!("{}/screen/graph", url))), 405);
+        assert_eq!(status_code(minreq::get(format!("{}/brightness", url))), 405);
+        assert_eq!(status_code(minreq::delete(format!("{}/message", url))), 405);
+    }
+
+    #[test]
+    fn status_json() {
+        let (url, _rx) = serve(None);
+
+        let response = minreq::get(format!("{}/status", url)).send().expect("a response");
+        let status: Value = serde_json::from_str(response.as_str().unwrap()).unwrap();
+        assert_eq!(status["brightness"], 100);
+        assert_eq!(status["display"], "on");
+        assert!(status["readings"].as_object().unwrap().is_empty());
+    }
+
+    #[test]
+    fn bearer_token() {
+        let (url, _rx) = serve(Some("secret"));
+        let status = format!("{}/status", url);
+
+        assert_eq!(status_code(minreq::get(&status)), 401);
+        assert_eq!(status_code(minreq::get(&status).with_header("Authorization", "Bearer wrong")), 401);
+        assert_eq!(status_code(minreq::get(&status).with_header("Authorization", "secret")), 401);
+        assert_eq!(status_code(minreq::get(&status).with_header("Authorization", "Bearer secret")), 200);
+
+        // Unknown paths are not given away either
+        assert_eq!(status_code(minreq::get(format!("{}/nothing", url))), 401);
+    }
+
+    #[test]
+    fn token_in_query() {
+        let (url, _rx) = serve(Some("secret"));
+
+        assert_eq!(status_code(minreq::get(format!("{}/status?token=secret", url))), 200);
+        assert_eq!(status_code(minreq::get(format!("{}/status?x=1&token=secret", url))), 200);
+        assert_eq!(status_code(minreq::get(format!("{}/status?token=wrong", url))), 401);
+        assert_eq!(status_code(minreq::get(format!("{}/status?token=secrets", url))), 401);
+
+        // The preview page itself is public, it asks for the token
+        assert_eq!(status_code(minreq::get(format!("{}/", url))), 200);
+    }
+
+    #[test]
+    fn input() {
+        let (url, rx) = serve(None);
+
+        for (input, event) in [
+            ("left", InputEvent::Left),
+            ("right", InputEvent::Right),
+            ("click", InputEvent::Click),
+            ("longpress", InputEvent::LongPress),
+        ] {
+            assert_eq!(status_code(minreq::post(format!("{}/input/{}", url, input))), 202);
+            assert_eq!(rx.try_recv(), Ok(Command::Input(event)));
+        }
+
+        assert_eq!(status_code(minreq::post(format!("{}/input/up", url))), 400);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn brightness() {
+        let (url, rx) = serve(None);
+        let brightness = format!("{}/brightness", url);
+
+        assert_eq!(status_code(minreq::post(&brightness).with_body("40")), 202);
+        assert_eq!(rx.try_recv(), Ok(Command::Brightness(40)));
+
+        assert_eq!(status_code(minreq::post(&brightness).with_body("140")), 400);
+        assert_eq!(status_code(minreq::post(&brightness)), 400);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn screen_message_and_display() {
+        let (url, rx) = serve(None);
+
+        assert_eq!(status_code(minreq::post(format!("{}/screen/graph", url))), 202);
+        assert_eq!(rx.try_recv(), Ok(Command::Screen("graph".to_string())));
+
+        assert_eq!(status_code(minreq::post(format!("{}/message", url)).with_body("Doorbell!")), 202);
+        assert_eq!(rx.try_recv(), Ok(Command::Message("Doorbell!".to_string())));
+
+        assert_eq!(status_code(minreq::post(format!("{}/display/off", url))), 202);
+        assert_eq!(rx.try_recv(), Ok(Command::Display(false)));
+        assert_eq!(status_code(minreq::post(format!("{}/display/dim", url))), 400);
+    }
+}
@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::screens::Framebuffer;
+
+// What the server said about the image, to ask it whether it changed since
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    url: String,
+    #[serde(flatten)]
+    validators: Validators,
+}
+
+// Downloaded backgrounds, already resized, so a station without network still
+// has them. One file per URL: a line of JSON with the URL and validators, then
+// the framebuffer's pixels. The least recently used go once over `limit` bytes.
+pub struct ImageCache {
+    dir: PathBuf,
+    limit: u64,
+}
+
+impl ImageCache {
+    pub fn new(dir: PathBuf, limit: u64) -> Self {
+        ImageCache { dir, limit }
+    }
+
+    pub fn load(&self, url: &str) -> io::Result<(Framebuffer, Validators)> {
+        let mut file = BufReader::new(fs::File::open(self.path(url))?);
+
+        let mut line = String::new();
+        file.read_line(&mut line)?;
+        let header: Header = serde_json::from_str(&line)?;
+        if header.url != url {
+            // Two URLs with the same hash, the other one is cached here
+            return Err(io::Error::new(io::ErrorKind::NotFound, "cached for another URL"));
+        }
+
+        let mut pixels = Vec::new();
+        file.read_to_end(&mut pixels)?;
+        let frame = Framebuffer::from_pixels(pixels)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "wrong size"))?;
+
+        Ok((frame, header.validators))
+    }
+
+    pub fn store(&self, url: &str, frame: &Framebuffer, validators: &Validators) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let header = Header {
+            url: url.to_string(),
+            validators: validators.clone(),
+        };
+
+        // Write next to it and rename, so a crash mid-write keeps the old image
+        let path = self.path(url);
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        file.write_all(frame.pixels())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+
+        self.prune()
+    }
+
+    // Marks it as used, the server said it has not changed
+    pub fn touch(&self, url: &str) -> io::Result<()> {
+        fs::File::options()
+            .write(true)
+            .open(self.path(url))?
+            .set_modified(SystemTime::now())
+    }
+
+    fn prune(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if entry.path().extension().map_or(false, |e| e == "img") {
+                entries.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+
+        // Newest first, whatever is past the limit goes
+        entries.sort_by(|a, b| b.0.cmp(&a.0));
+        let mut total = 0;
+        for (_, size, path) in entries {
+            total += size;
+            if total > self.limit {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.img", fnv1a(url.as_bytes())))
+    }
+}
+
+// Stable across builds, unlike the standard library's hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screens::{HEIGHT, WIDTH};
+    use std::time::Duration;
+
+    fn frame(shade: u8) -> Framebuffer {
+        Framebuffer::from_pixels(vec![shade; (WIDTH * HEIGHT * 3) as usize]).expect("a frame")
+    }
+
+    fn age(cache: &ImageCache, url: &str, minutes: u64) {
+        fs::File::options()
+            .write(true)
+            .open(cache.path(url))
+            .expect("open")
+            .set_modified(SystemTime::now() - Duration::from_secs(minutes * 60))
+            .expect("set modified");
+    }
+
+    #[test]
+    fn stored_images_come_back() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let cache = ImageCache::new(dir.path().join("backgrounds"), 1024 * 1024);
+        let validators = Validators {
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+        };
+
+        cache.store("http://example.com/a.png", &frame(7), &validators).expect("store");
+        let (loaded, loaded_validators) = cache.load("http://example.com/a.png").expect("load");
+        assert_eq!(loaded.pixels(), frame(7).pixels());
+        assert_eq!(loaded_validators.etag, validators.etag);
+        assert_eq!(loaded_validators.last_modified, None);
+
+        let e = cache.load("http://example.com/b.png").err().expect("not cached");
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn only_the_url_it_was_stored_for() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let cache = ImageCache::new(dir.path().to_path_buf(), 1024 * 1024);
+
+        // As if the two hashed the same
+        cache.store("http://example.com/a.png", &frame(7), &Validators::default()).expect("store");
+        fs::rename(cache.path("http://example.com/a.png"), cache.path("http://example.com/b.png"))
+            .expect("rename");
+        let e = cache.load("http://example.com/b.png").err().expect("another URL");
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn truncated_images_are_rejected() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let cache = ImageCache::new(dir.path().to_path_buf(), 1024 * 1024);
+        let url = "http://example.com/a.png";
+
+        cache.store(url, &frame(7), &Validators::default()).expect("store");
+        let data = fs::read(cache.path(url)).expect("read");
+        fs::write(cache.path(url), &data[..data.len() - 3]).expect("write");
+        let e = cache.load(url).err().expect("truncated");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn least_recently_used_go_first() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let urls = [
+            "http://example.com/a.png",
+            "http://example.com/b.png",
+            "http://example.com/c.png",
+        ];
+
+        // Room for two
+        let size = {
+            let cache = ImageCache::new(dir.path().to_path_buf(), u64::MAX);
+            cache.store(urls[0], &frame(1), &Validators::default()).expect("store");
+            fs::metadata(cache.path(urls[0])).expect("metadata").len()
+        };
+        let cache = ImageCache::new(dir.path().to_path_buf(), size * 2);
+        cache.store(urls[1], &frame(2), &Validators::default()).expect("store");
+        age(&cache, urls[0], 2);
+        age(&cache, urls[1], 1);
+
+        // a is older, but was used since
+        cache.touch(urls[0]).expect("touch");
+        cache.store(urls[2], &frame(3), &Validators::default()).expect("store");
+
+        assert!(cache.load(urls[0]).is_ok());
+        assert!(cache.load(urls[1]).is_err());
+        assert!(cache.load(urls[2]).is_ok());
+    }
+
+    #[test]
+    fn hash() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::rotary::InputEvent;
+
+// Something asked of the display from outside the rotary encoder. The main
+// loop handles these together with the encoder's own events.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Input(InputEvent),
+    Screen(String), // by name
+    Brightness(u8), // percent
+    Message(String),
+    Display(bool),  // on or off
+}
+
+impl Command {
+    // Parses a command by name with its argument, e.g. ("brightness", "40")
+    pub fn parse(name: &str, argument: &str) -> Result<Command, String> {
+        let argument = argument.trim();
+
+        match name {
+            "input" => match argument {
+                "left" => Ok(Command::Input(InputEvent::Left)),
+                "right" => Ok(Command::Input(InputEvent::Right)),
+                "click" => Ok(Command::Input(InputEvent::Click)),
+                "long_press" | "longpress" => Ok(Command::Input(InputEvent::LongPress)),
+                other => Err(format!("unknown input `{}`", other)),
+            },
+            "click" => Ok(Command::Input(InputEvent::Click)),
+            "screen" => Ok(Command::Screen(argument.to_string())),
+            "brightness" => match argument.parse() {
+                Ok(percent) if percent <= 100 => Ok(Command::Brightness(percent)),
+                _ => Err(format!("brightness should be 0-100, not `{}`", argument)),
+            },
+            "message" => Ok(Command::Message(argument.to_string())),
+            "display" => match argument {
+                "on" => Ok(Command::Display(true)),
+                "off" => Ok(Command::Display(false)),
+                other => Err(format!("display should be on or off, not `{}`", other)),
+            },
+            other => Err(format!("unknown command `{}`", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inputs() {
+        assert_eq!(Command::parse("input", "left"), Ok(Command::Input(InputEvent::Left)));
+        assert_eq!(Command::parse("input", " right\n"), Ok(Command::Input(InputEvent::Right)));
+        assert_eq!(Command::parse("input", "click"), Ok(Command::Input(InputEvent::Click)));
+        assert_eq!(Command::parse("input", "long_press"), Ok(Command::Input(InputEvent::LongPress)));
+        assert_eq!(Command::parse("input", "longpress"), Ok(Command::Input(InputEvent::LongPress)));
+        assert_eq!(Command::parse("click", ""), Ok(Command::Input(InputEvent::Click)));
+        assert_eq!(Command::parse("input", "up"), Err("unknown input `up`".to_string()));
+    }
+
+    #[test]
+    fn brightness() {
+        assert_eq!(Command::parse("brightness", "0"), Ok(Command::Brightness(0)));
+        assert_eq!(Command::parse("brightness", "100"), Ok(Command::Brightness(100)));
+        for bad in ["101", "-1", "40%", ""] {
+            let e = Command::parse("brightness", bad).unwrap_err();
+            assert_eq!(e, format!("brightness should be 0-100, not `{}`", bad));
+        }
+    }
+
+    #[test]
+    fn others() {
+        assert_eq!(Command::parse("screen", "graph"), Ok(Command::Screen("graph".to_string())));
+        assert_eq!(
+            Command::parse("message", " Time for a break "),
+            Ok(Command::Message("Time for a break".to_string()))
+        );
+        assert_eq!(Command::parse("display", "on"), Ok(Command::Display(true)));
+        assert_eq!(Command::parse("display", "off"), Ok(Command::Display(false)));
+        assert!(Command::parse("display", "dim").is_err());
+        assert_eq!(Command::parse("reboot", ""), Err("unknown command `reboot`".to_string()));
+    }
+}
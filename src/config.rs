@@ -0,0 +1,583 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::NaiveTime;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::logging;
+use crate::sensors::Metric;
+
+const DEFAULT_PATH: &str = "/etc/leddy.toml";
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub sensors: SensorsConfig,
+    pub scd30: Scd30Config,
+    pub storage: StorageConfig,
+    pub alerts: AlertsConfig,
+    pub background: BackgroundConfig,
+    pub display: DisplayConfig,
+    pub log: LogConfig,
+    pub shutdown: ShutdownConfig,
+    pub metrics: MetricsConfig,
+    pub mqtt: MqttConfig,
+    pub api: ApiConfig,
+    pub control: ControlConfig,
+    pub influxdb: InfluxConfig,
+    pub webhook: WebhookConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SensorsConfig {
+    pub bus: String, // probed for every supported sensor
+}
+
+impl Default for SensorsConfig {
+    fn default() -> Self {
+        SensorsConfig {
+            bus: "/dev/i2c-1".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub enabled: bool,
+    pub dir: String,
+    pub retention_days: u32, // 0 keeps everything
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            enabled: true,
+            dir: "/var/lib/leddy".to_string(),
+            retention_days: 90,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DisplayConfig {
+    pub temperature_unit: TemperatureUnit, // C, F or K
+    pub clock: Clock,                      // 24h or 12h
+    pub seconds: bool,
+    #[serde(deserialize_with = "date_format")]
+    pub date_format: Option<String>,       // strftime style, e.g. "%a %d %b"
+    pub decimal_separator: char,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            temperature_unit: TemperatureUnit::Celsius,
+            clock: Clock::TwentyFourHour,
+            seconds: true,
+            date_format: None,
+            decimal_separator: '.',
+        }
+    }
+}
+
+// Checked up front, chrono only notices a bad specifier while formatting
+fn date_format<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let format = String::deserialize(deserializer)?;
+    if StrftimeItems::new(&format).any(|item| matches!(item, Item::Error)) {
+        return Err(serde::de::Error::custom(format!("invalid date_format `{}`", format)));
+    }
+    Ok(Some(format))
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TemperatureUnit {
+    #[serde(rename = "C")]
+    Celsius,
+    #[serde(rename = "F")]
+    Fahrenheit,
+    #[serde(rename = "K")]
+    Kelvin,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Clock {
+    #[serde(rename = "24h")]
+    TwentyFourHour,
+    #[serde(rename = "12h")]
+    TwelveHour,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BackgroundConfig {
+    pub metrics: Option<Vec<Metric>>, // in the order given, e.g. ["co2", "dew_point"]
+    pub sources: Vec<String>,         // image URLs, any size, cropped and scaled to fit
+    pub refresh_interval: u64,        // seconds, downloaded again after this long, 0 for never
+    pub cache_size: u64,              // bytes of resized images kept for offline starts, 0 for none
+}
+
+impl Default for BackgroundConfig {
+    fn default() -> Self {
+        BackgroundConfig {
+            metrics: None,
+            sources: vec![
+                "https://c4.wallpaperflare.com/wallpaper/765/580/971/digital-art-pixel-art-pixels-landscape-wallpaper-preview.jpg".to_string(),
+                "https://c4.wallpaperflare.com/wallpaper/406/189/125/digital-art-pixel-art-pixelated-pixels-wallpaper-preview.jpg".to_string(),
+                "https://wallpaperaccess.com/full/2122578.jpg".to_string(),
+            ],
+            refresh_interval: 24 * 60 * 60,
+            cache_size: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AlertsConfig {
+    pub enabled: bool,
+    #[serde(deserialize_with = "thresholds")]
+    pub thresholds: Vec<f32>,           // ppm CO2, in any order
+    pub hysteresis: f32,                // ppm below a threshold before it clears
+    #[serde(deserialize_with = "hh_mm")]
+    pub quiet_start: Option<NaiveTime>, // HH:MM
+    #[serde(deserialize_with = "hh_mm")]
+    pub quiet_end: Option<NaiveTime>,   // HH:MM
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        AlertsConfig {
+            enabled: true,
+            thresholds: vec![1000.0, 1400.0],
+            hysteresis: 100.0,
+            quiet_start: None,
+            quiet_end: None,
+        }
+    }
+}
+
+// Any order is fine, the alert levels go from the lowest up
+fn thresholds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f32>, D::Error> {
+    let mut thresholds = Vec::<f32>::deserialize(deserializer)?;
+    if let Some(bad) = thresholds.iter().find(|t| !t.is_finite()) {
+        return Err(serde::de::Error::custom(format!("threshold should be a number, not {}", bad)));
+    }
+    thresholds.sort_by(f32::total_cmp);
+    Ok(thresholds)
+}
+
+// chrono only speaks serde with a feature we do not pull in
+fn hh_mm<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<NaiveTime>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&text, "%H:%M")
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom(format!("expected HH:MM, not `{}`", text)))
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LogConfig {
+    #[serde(deserialize_with = "log_level")]
+    pub level: LevelFilter,                     // off, error, warn, info, debug or trace
+    #[serde(deserialize_with = "log_levels")]
+    pub modules: BTreeMap<String, LevelFilter>, // per module levels, e.g. { mqtt = "debug" }
+    pub file: Option<String>,                   // also log here, besides stderr
+    pub max_size: u64,                          // bytes, the file is rotated past this, 0 never rotates
+    pub keep: usize,                            // rotated files kept
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LevelFilter::Info,
+            modules: BTreeMap::new(),
+            file: None,
+            max_size: 1024 * 1024,
+            keep: 3,
+        }
+    }
+}
+
+fn log_level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<LevelFilter, D::Error> {
+    logging::parse_level(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn log_levels<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, LevelFilter>, D::Error> {
+    BTreeMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(module, level)| Ok((module, logging::parse_level(&level)?)))
+        .collect::<Result<_, String>>()
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub goodbye: GoodbyeStyle, // fade, text or none
+    pub text: String,          // shown when goodbye is text
+    pub duration: u64,         // ms the goodbye takes
+    pub timeout: u64,          // seconds to wait for the workers to stop
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum GoodbyeStyle {
+    Fade,
+    Text,
+    None,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            goodbye: GoodbyeStyle::Fade,
+            text: "Bye!".to_string(),
+            duration: 1000,
+            timeout: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub listen: String, // address for the Prometheus /metrics endpoint
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            listen: "0.0.0.0:9521".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct InfluxConfig {
+    pub enabled: bool,
+    pub url: String,                    // the full write URL, with org, bucket and precision=s
+    pub token: Option<String>,
+    pub measurement: String,
+    pub tags: BTreeMap<String, String>, // added to every point, e.g. { room = "office" }
+    pub batch_size: usize,              // readings per request
+    pub flush_interval: u64,            // seconds, push a partial batch after this long
+    pub queue_limit: usize,             // readings kept while InfluxDB is unreachable
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        InfluxConfig {
+            enabled: false,
+            url: "http://localhost:8086/api/v2/write?org=home&bucket=leddy&precision=s".to_string(),
+            token: None,
+            measurement: "leddy".to_string(),
+            tags: BTreeMap::new(),
+            batch_size: 60,
+            flush_interval: 60,
+            queue_limit: 100_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub enabled: bool,
+    pub url: String,
+    pub headers: BTreeMap<String, String>, // e.g. { Authorization = "Bearer ..." }
+    pub batch_size: usize,
+    pub flush_interval: u64,               // seconds
+    pub queue_limit: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            enabled: false,
+            url: String::new(),
+            headers: BTreeMap::new(),
+            batch_size: 60,
+            flush_interval: 60,
+            queue_limit: 100_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub listen: String,
+    pub token: Option<String>, // required as `Authorization: Bearer <token>` if set
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            enabled: false,
+            listen: "0.0.0.0:8080".to_string(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ControlConfig {
+    pub enabled: bool,
+    pub socket: String, // Unix socket for `leddy ctl`
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig {
+            enabled: false,
+            socket: "/run/leddy/control.sock".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,        // also the Home Assistant device name
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub prefix: String,           // readings go to <prefix>/<metric>
+    pub qos: Qos,                 // 0, 1 or 2
+    pub retain: bool,
+    pub discovery: bool,          // Home Assistant MQTT discovery
+    pub discovery_prefix: String,
+    pub buffer: usize,            // messages kept while the broker is unreachable
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "leddy".to_string(),
+            username: None,
+            password: None,
+            prefix: "leddy".to_string(),
+            qos: Qos::AtMostOnce,
+            retain: true,
+            discovery: true,
+            discovery_prefix: "homeassistant".to_string(),
+            buffer: 1000,
+        }
+    }
+}
+
+// MQTT delivery guarantee, given as 0, 1 or 2 like everywhere else
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(try_from = "u8")]
+pub enum Qos {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl TryFrom<u8> for Qos {
+    type Error = String;
+
+    fn try_from(qos: u8) -> Result<Self, String> {
+        match qos {
+            0 => Ok(Qos::AtMostOnce),
+            1 => Ok(Qos::AtLeastOnce),
+            2 => Ok(Qos::ExactlyOnce),
+            other => Err(format!("qos should be 0, 1 or 2, not {}", other)),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Scd30Config {
+    pub measurement_interval: u16,       // seconds
+    pub pressure: u16,                   // hPa, 0 disables (altitude is used instead)
+    pub altitude: Option<u16>,           // meters above sea level
+    pub temperature_offset: Option<f32>, // ºC subtracted from the reading
+    pub auto_calibration: Option<bool>,
+    #[serde(deserialize_with = "calibration_reference")]
+    pub calibration_reference: u16,      // ppm used for forced recalibration
+}
+
+impl Default for Scd30Config {
+    fn default() -> Self {
+        Scd30Config {
+            measurement_interval: 2,
+            pressure: 1004,
+            altitude: None,
+            temperature_offset: None,
+            auto_calibration: None,
+            calibration_reference: 420,
+        }
+    }
+}
+
+// The SCD30 only accepts a forced recalibration reference in this range
+fn calibration_reference<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let ppm = u16::deserialize(deserializer)?;
+    if !(400..=2000).contains(&ppm) {
+        return Err(serde::de::Error::custom(format!(
+            "calibration_reference should be 400-2000 ppm, not {}",
+            ppm
+        )));
+    }
+    Ok(ppm)
+}
+
+// Reads the config from $LEDDY_CONFIG or /etc/leddy.toml, falling back to
+// defaults if there is none. Anything invalid is caught here, with what and
+// where, rather than once the display is running.
+pub fn load() -> Result<Config, String> {
+    let path = std::env::var("LEDDY_CONFIG").unwrap_or_else(|_| DEFAULT_PATH.to_string());
+
+    if !Path::new(&path).exists() {
+        return Ok(Config::default());
+    }
+
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    toml::from_str(&text).map_err(|e| format!("invalid config {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|e: toml::de::Error| e.to_string())
+    }
+
+    #[test]
+    fn everything_has_a_default() {
+        let config = parse("").expect("empty config");
+        assert_eq!(config.display.temperature_unit, TemperatureUnit::Celsius);
+        assert_eq!(config.display.clock, Clock::TwentyFourHour);
+        assert_eq!(config.display.decimal_separator, '.');
+    }
+
+    #[test]
+    fn display_settings() {
+        let config = parse(
+            r#"
+            [display]
+            temperature_unit = "F"
+            clock = "12h"
+            decimal_separator = ","
+            date_format = "%a %d %b"
+            "#,
+        )
+        .expect("valid config");
+        assert_eq!(config.display.temperature_unit, TemperatureUnit::Fahrenheit);
+        assert_eq!(config.display.clock, Clock::TwelveHour);
+        assert_eq!(config.display.decimal_separator, ',');
+        assert_eq!(config.display.date_format.as_deref(), Some("%a %d %b"));
+    }
+
+    #[test]
+    fn bad_display_settings_are_rejected() {
+        for (setting, value) in [
+            ("temperature_unit", "\"X\""),
+            ("clock", "\"13h\""),
+            ("decimal_separator", "\",,\""),
+            ("date_format", "\"%a %Q\""),
+        ] {
+            let e = parse(&format!("[display]\n{} = {}", setting, value)).unwrap_err();
+            assert!(e.contains(&value[1..value.len() - 1]), "{}", e);
+        }
+    }
+
+    #[test]
+    fn background_metrics() {
+        let config = parse("[background]\nmetrics = [\"co2\", \"dew_point\"]").expect("valid metrics");
+        assert_eq!(config.background.metrics, Some(vec![Metric::Co2, Metric::DewPoint]));
+
+        let e = parse("[background]\nmetrics = [\"co2\", \"radon\"]").unwrap_err();
+        assert!(e.contains("unknown metric `radon`"), "{}", e);
+    }
+
+    #[test]
+    fn alerts() {
+        let config = parse(
+            r#"
+            [alerts]
+            thresholds = [1400, 1000]
+            quiet_start = "22:00"
+            quiet_end = "07:30"
+            "#,
+        )
+        .expect("valid alerts");
+        assert_eq!(config.alerts.thresholds, vec![1000.0, 1400.0]);
+        assert_eq!(config.alerts.quiet_start, NaiveTime::from_hms_opt(22, 0, 0));
+        assert_eq!(config.alerts.quiet_end, NaiveTime::from_hms_opt(7, 30, 0));
+
+        let e = parse("[alerts]\nquiet_start = \"10pm\"").unwrap_err();
+        assert!(e.contains("expected HH:MM, not `10pm`"), "{}", e);
+
+        let e = parse("[alerts]\nthresholds = [1000, nan]").unwrap_err();
+        assert!(e.contains("threshold should be a number"), "{}", e);
+    }
+
+    #[test]
+    fn log_settings() {
+        let config = parse(
+            r#"
+            [log]
+            level = "warn"
+            modules = { mqtt = "debug" }
+            "#,
+        )
+        .expect("valid levels");
+        assert_eq!(config.log.level, LevelFilter::Warn);
+        assert_eq!(config.log.modules["mqtt"], LevelFilter::Debug);
+
+        let e = parse("[log]\nlevel = \"loud\"").unwrap_err();
+        assert!(e.contains("not `loud`"), "{}", e);
+        let e = parse("[log]\nmodules = { mqtt = \"chatty\" }").unwrap_err();
+        assert!(e.contains("not `chatty`"), "{}", e);
+    }
+
+    #[test]
+    fn goodbye_style() {
+        let config = parse("[shutdown]\ngoodbye = \"none\"").expect("valid goodbye");
+        assert_eq!(config.shutdown.goodbye, GoodbyeStyle::None);
+
+        let e = parse("[shutdown]\ngoodbye = \"wave\"").unwrap_err();
+        assert!(e.contains("wave"), "{}", e);
+    }
+
+    #[test]
+    fn mqtt_qos() {
+        let config = parse("[mqtt]\nqos = 2").expect("valid qos");
+        assert_eq!(config.mqtt.qos, Qos::ExactlyOnce);
+
+        let e = parse("[mqtt]\nqos = 3").unwrap_err();
+        assert!(e.contains("qos should be 0, 1 or 2, not 3"), "{}", e);
+    }
+
+    #[test]
+    fn calibration_reference() {
+        let config = parse("[scd30]\ncalibration_reference = 450").expect("valid reference");
+        assert_eq!(config.scd30.calibration_reference, 450);
+
+        for ppm in [0, 399, 2001] {
+            let e = parse(&format!("[scd30]\ncalibration_reference = {}", ppm)).unwrap_err();
+            assert!(e.contains(&format!("400-2000 ppm, not {}", ppm)), "{}", e);
+        }
+    }
+}
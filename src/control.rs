@@ -0,0 +1,331 @@
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::api::{self, SharedDisplayState};
+use crate::commands::Command;
+use crate::config::{Config, ControlConfig};
+use crate::history::SharedHistory;
+use crate::preview::SharedFrame;
+use crate::screens::{HEIGHT, WIDTH};
+use crate::shutdown::Workers;
+
+// How often blocked accepts and reads look at the cancel flag
+const POLL: Duration = Duration::from_millis(500);
+
+const USAGE: &str = "usage: leddy ctl COMMAND [ARGUMENT]
+
+Commands:
+  status                 what is shown and the latest readings
+  screens                the screens there are
+  screen NAME            switch to a screen
+  input left|right|click|long_press
+  brightness 0-100
+  message TEXT           show a message for a while
+  display on|off
+  screenshot FILE        save what is on the panel as a PPM image";
+
+// Everything a connection needs, shared between them
+struct Control {
+    commands: Sender<Command>,
+    display: SharedDisplayState,
+    history: SharedHistory,
+    frame: SharedFrame,
+    screens: Vec<&'static str>,
+    started: Instant,
+}
+
+// Listens on a Unix socket for one JSON request per line, e.g.
+// {"command": "screen", "argument": "maze"}, and answers each with a line of JSON
+pub fn spawn(
+    config: &ControlConfig,
+    commands: Sender<Command>,
+    display: SharedDisplayState,
+    history: SharedHistory,
+    frame: SharedFrame,
+    screens: Vec<&'static str>,
+    workers: Workers,
+    cancel: Arc<AtomicBool>,
+) -> Option<JoinHandle<()>> {
+    let path = Path::new(&config.socket);
+    let listener = match bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Control socket disabled, cannot listen on {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    info!("Listening for commands on {}", path.display());
+
+    let control = Arc::new(Control {
+        commands,
+        display,
+        history,
+        frame,
+        screens,
+        started: Instant::now(),
+    });
+    let path = path.to_path_buf();
+
+    Some(thread::spawn(move || {
+        while !cancel.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let control = Arc::clone(&control);
+                    let cancel = Arc::clone(&cancel);
+                    let handle = thread::spawn(move || {
+                        if let Err(e) = control.serve(stream, &cancel) {
+                            warn!("Control connection error: {}", e);
+                        }
+                    });
+                    workers.add("control connection", handle);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL),
+                Err(e) => {
+                    error!("Control socket error: {}", e);
+                    thread::sleep(POLL);
+                }
+            }
+        }
+
+        let _ = fs::remove_file(&path);
+    }))
+}
+
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        // Taking over the socket of a leddy that is still running would leave it deaf
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(ErrorKind::AddrInUse, "another leddy is listening on it"));
+        }
+        // Left behind by a previous run that did not get to clean up
+        fs::remove_file(path)?;
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+impl Control {
+    fn serve(&self, stream: UnixStream, cancel: &AtomicBool) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(POLL))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        let mut line = String::new();
+
+        while !cancel.load(Ordering::Relaxed) {
+            match reader.read_line(&mut line) {
+                Ok(0) => break, // closed
+                Ok(_) => {
+                    let response = self.handle(&line);
+                    writeln!(writer, "{}", response)?;
+                    line.clear();
+                }
+                // What was read so far stays in `line`
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, line: &str) -> Value {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return json!({ "error": format!("bad request: {}", e) }),
+        };
+
+        let name = match request["command"].as_str() {
+            Some(name) => name,
+            None => return json!({ "error": "missing command" }),
+        };
+        let argument = request["argument"].as_str().unwrap_or_default();
+
+        match name {
+            "status" => api::status(&self.display, &self.history, self.started),
+            "screens" => json!({ "screens": self.screens }),
+            "screenshot" => {
+                let frame = self.frame.lock().expect("lock preview frame");
+                json!({ "width": WIDTH, "height": HEIGHT, "pixels": hex(&frame.pixels) })
+            }
+            // The rest is for the main loop, same as over MQTT and HTTP
+            name => match Command::parse(name, argument) {
+                Ok(command) => match self.commands.send(command) {
+                    Ok(()) => json!({ "ok": true }),
+                    Err(_) => json!({ "error": "display is shutting down" }),
+                },
+                Err(e) => json!({ "error": e }),
+            },
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+// `leddy ctl`, sends one request to a running leddy and prints the answer
+pub fn run(config: &Config, args: &[String]) -> Result<(), String> {
+    // Messages with spaces do not need quoting
+    let (name, argument) = match args {
+        [name, rest @ ..] => (name.as_str(), rest.join(" ")),
+        [] => return Err(USAGE.to_string()),
+    };
+
+    if matches!(name, "-h" | "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    // The file is written here, the display only hands over the pixels
+    if name == "screenshot" {
+        if argument.is_empty() {
+            return Err(format!("screenshot needs a file\n\n{}", USAGE));
+        }
+        let response = request(config, name, "")?;
+        return save_ppm(&response, Path::new(&argument));
+    }
+
+    let response = request(config, name, &argument)?;
+    println!("{}", serde_json::to_string_pretty(&response).map_err(|e| e.to_string())?);
+    Ok(())
+}
+
+fn request(config: &Config, name: &str, argument: &str) -> Result<Value, String> {
+    let socket = &config.control.socket;
+    let stream = UnixStream::connect(socket).map_err(|e| {
+        format!("cannot connect to {}, is leddy running with [control] enabled? {}", socket, e)
+    })?;
+
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    writeln!(writer, "{}", json!({ "command": name, "argument": argument }))
+        .map_err(|e| e.to_string())?;
+
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(|e| format!("no answer: {}", e))?;
+    let response: Value = serde_json::from_str(&line).map_err(|e| format!("bad answer: {}", e))?;
+
+    match response["error"].as_str() {
+        Some(e) => Err(e.to_string()),
+        None => Ok(response),
+    }
+}
+
+fn save_ppm(response: &Value, path: &Path) -> Result<(), String> {
+    let width = response["width"].as_u64().ok_or("screenshot without width")?;
+    let height = response["height"].as_u64().ok_or("screenshot without height")?;
+    let pixels = response["pixels"]
+        .as_str()
+        .and_then(unhex)
+        .ok_or("screenshot without pixels")?;
+
+    let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    ppm.extend_from_slice(&pixels);
+    fs::write(path, ppm).map_err(|e| format!("cannot write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::DisplayState;
+    use crate::history::History;
+    use crate::preview::Frame;
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn control() -> (Control, Receiver<Command>) {
+        let (tx, rx) = channel();
+        let control = Control {
+            commands: tx,
+            display: DisplayState::shared(),
+            history: History::shared(),
+            frame: Frame::shared(WIDTH, HEIGHT),
+            screens: vec!["background", "maze"],
+            started: Instant::now(),
+        };
+        (control, rx)
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(hex(&[0x00, 0x7f, 0xff]), "007fff");
+        assert_eq!(unhex("007fff"), Some(vec![0x00, 0x7f, 0xff]));
+        assert_eq!(unhex("007FFF"), Some(vec![0x00, 0x7f, 0xff]));
+        assert_eq!(unhex(""), Some(vec![]));
+
+        assert_eq!(unhex("007"), None);
+        assert_eq!(unhex("zz"), None);
+        assert_eq!(unhex("0ä"), None);
+    }
+
+    #[test]
+    fn commands_go_to_the_main_loop() {
+        let (control, rx) = control();
+
+        let response = control.handle(r#"{"command": "screen", "argument": "maze"}"#);
+        assert_eq!(response, json!({ "ok": true }));
+        assert_eq!(rx.try_recv(), Ok(Command::Screen("maze".to_string())));
+
+        let response = control.handle(r#"{"command": "screens"}"#);
+        assert_eq!(response, json!({ "screens": ["background", "maze"] }));
+
+        let response = control.handle(r#"{"command": "screenshot"}"#);
+        let pixels = response["pixels"].as_str().and_then(unhex).expect("pixels");
+        assert_eq!(pixels.len(), (WIDTH * HEIGHT * 3) as usize);
+    }
+
+    #[test]
+    fn errors() {
+        let (control, rx) = control();
+        let error = |line: &str| control.handle(line)["error"].as_str().unwrap_or_default().to_string();
+
+        assert!(error("not json").starts_with("bad request: "));
+        assert_eq!(error(r#"{"argument": "maze"}"#), "missing command");
+        assert_eq!(error(r#"{"command": 7}"#), "missing command");
+        assert_eq!(error(r#"{"command": "explode"}"#), "unknown command `explode`");
+        assert_eq!(
+            error(r#"{"command": "brightness", "argument": "140"}"#),
+            "brightness should be 0-100, not `140`"
+        );
+        assert!(rx.try_recv().is_err());
+
+        drop(rx);
+        assert_eq!(error(r#"{"command": "click"}"#), "display is shutting down");
+    }
+
+    #[test]
+    fn bind_refuses_a_socket_in_use() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let path = dir.path().join("control.sock");
+
+        let listener = bind(&path).expect("bind");
+        let e = bind(&path).expect_err("in use");
+        assert_eq!(e.kind(), ErrorKind::AddrInUse);
+
+        // Nobody answers on what is left behind
+        drop(listener);
+        assert!(path.exists());
+        bind(&path).expect("bind over a stale socket");
+    }
+}
@@ -0,0 +1,281 @@
+use image::io::Reader as ImageReader;
+use log::{debug, info, warn};
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::cache::{ImageCache, Validators};
+use crate::config::Config;
+use crate::metrics::SharedMetrics;
+use crate::screens::Framebuffer;
+
+// First retry after a failed download, doubled up to RETRY_MAX
+const RETRY_MIN: Duration = Duration::from_secs(10);
+const RETRY_MAX: Duration = Duration::from_secs(60 * 60);
+
+// How often to look at the cancel flag while nothing is due
+const POLL: Duration = Duration::from_millis(500);
+
+// Seconds, so a dead server does not hold up shutdown for long. Never more
+// than the shutdown timeout, that is how long we are waited for.
+const TIMEOUT: u64 = 10;
+
+// An image for the background screen, by its index in `sources`
+pub type Download = (usize, Framebuffer);
+
+struct Source {
+    url: String,
+    next_attempt: Option<Instant>, // None once it is not to be fetched again
+    backoff: Duration,
+    validators: Validators, // of the copy we have, if any
+}
+
+enum Fetched {
+    Changed(Framebuffer, Validators),
+    Unchanged,
+}
+
+// Downloads the background images one by one and hands them over as they
+// arrive, so the panel is not dark while the network is slow. Failures are
+// retried with backoff, and every image is fetched again now and then in case
+// it changed. Whatever is in the cache is handed over first, before any of that.
+pub fn spawn(
+    config: &Config,
+    tx: Sender<Download>,
+    metrics: SharedMetrics,
+    cancel: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    // 0 downloads every image once and keeps it
+    let refresh = match config.background.refresh_interval {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    };
+    let timeout = TIMEOUT.min(config.shutdown.timeout).max(1);
+    let dir = PathBuf::from(&config.storage.dir).join("backgrounds");
+    let cache = match config.background.cache_size {
+        0 => None,
+        size => Some(ImageCache::new(dir, size)),
+    };
+    let mut sources: Vec<Source> = config
+        .background
+        .sources
+        .iter()
+        .map(|url| Source {
+            url: url.clone(),
+            next_attempt: Some(Instant::now()),
+            backoff: RETRY_MIN,
+            validators: Validators::default(),
+        })
+        .collect();
+
+    thread::spawn(move || {
+        if let Some(cache) = &cache {
+            for (index, source) in sources.iter_mut().enumerate() {
+                match cache.load(&source.url) {
+                    Ok((frame, validators)) => {
+                        debug!("Loaded background {} from the cache", source.url);
+                        source.validators = validators;
+                        if tx.send((index, frame)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => warn!("Ignoring cached background {}: {}", source.url, e),
+                }
+            }
+        }
+
+        while !cancel.load(Ordering::Relaxed) {
+            let due = sources
+                .iter()
+                .position(|s| matches!(s.next_attempt, Some(at) if at <= Instant::now()));
+            let (index, source) = match due {
+                Some(index) => (index, &mut sources[index]),
+                None => {
+                    thread::sleep(POLL);
+                    continue;
+                }
+            };
+
+            match fetch(&source.url, &source.validators, timeout) {
+                Ok(Fetched::Changed(frame, validators)) => {
+                    debug!("Downloaded background {}", source.url);
+                    source.next_attempt = refresh.map(|refresh| Instant::now() + refresh);
+                    source.backoff = RETRY_MIN;
+
+                    if let Some(cache) = &cache {
+                        if let Err(e) = cache.store(&source.url, &frame, &validators) {
+                            warn!("Could not cache background {}: {}", source.url, e);
+                        }
+                    }
+                    source.validators = validators;
+
+                    if tx.send((index, frame)).is_err() {
+                        break; // the screen is gone
+                    }
+                }
+                Ok(Fetched::Unchanged) => {
+                    debug!("Background {} has not changed", source.url);
+                    source.next_attempt = refresh.map(|refresh| Instant::now() + refresh);
+                    source.backoff = RETRY_MIN;
+
+                    if let Some(cache) = &cache {
+                        if let Err(e) = cache.touch(&source.url) {
+                            warn!("Could not update cached background {}: {}", source.url, e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not download background {}, retrying in {:?}: {}",
+                        source.url, source.backoff, e
+                    );
+                    metrics.background_failure();
+                    source.next_attempt = Some(Instant::now() + source.backoff);
+                    source.backoff = (source.backoff * 2).min(RETRY_MAX);
+                }
+            }
+        }
+
+        info!("Background downloads stopped");
+    })
+}
+
+// Asks for the image only if it changed since the copy `validators` came with
+fn fetch(
+    url: &str,
+    validators: &Validators,
+    timeout: u64,
+) -> Result<Fetched, Box<dyn std::error::Error>> {
+    let mut request = minreq::get(url).with_timeout(timeout);
+    if let Some(etag) = &validators.etag {
+        request = request.with_header("If-None-Match", etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.with_header("If-Modified-Since", last_modified);
+    }
+
+    let response = request.send()?;
+    if response.status_code == 304 {
+        return Ok(Fetched::Unchanged);
+    }
+    if !(200..300).contains(&response.status_code) {
+        return Err(format!("HTTP {} {}", response.status_code, response.reason_phrase).into());
+    }
+
+    // minreq has the header names in lowercase
+    let validators = Validators {
+        etag: response.headers.get("etag").cloned(),
+        last_modified: response.headers.get("last-modified").cloned(),
+    };
+
+    let orig = ImageReader::new(Cursor::new(response.into_bytes()))
+        .with_guessed_format()?
+        .decode()?;
+
+    Ok(Fetched::Changed(Framebuffer::from_image(&orig), validators))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackgroundConfig;
+    use crate::metrics::Metrics;
+    use image::ImageFormat;
+    use std::sync::mpsc::channel;
+    use tiny_http::{Header, Response, Server};
+
+    static IMAGE: &[u8] = include_bytes!("../sakura-bg.bmp");
+
+    // Serves IMAGE at any path but /missing.png, with an ETag
+    fn serve() -> String {
+        let server = Server::http("127.0.0.1:0").expect("listen");
+        let port = server.server_addr().to_ip().expect("an IP address").port();
+
+        let etag = Header::from_bytes(&b"ETag"[..], &b"\"v1\""[..]).expect("valid header");
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let cached = request
+                    .headers()
+                    .iter()
+                    .any(|h| h.field.equiv("If-None-Match") && h.value.as_str() == "\"v1\"");
+                let response = match request.url() {
+                    "/missing.png" => Response::from_string("Not found").with_status_code(404),
+                    _ if cached => Response::from_string("").with_status_code(304),
+                    _ => Response::from_data(IMAGE).with_header(etag.clone()),
+                };
+                request.respond(response).ok();
+            }
+        });
+
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    fn config(sources: Vec<String>) -> Config {
+        Config {
+            background: BackgroundConfig {
+                sources,
+                cache_size: 0,
+                ..BackgroundConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn downloads_are_decoded() {
+        let url = serve();
+        let (tx, rx) = channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let config = config(vec![format!("{}/sakura.bmp", url)]);
+        let handle = spawn(&config, tx, Metrics::shared(), Arc::clone(&cancel));
+
+        let (index, frame) = rx.recv_timeout(Duration::from_secs(5)).expect("a download");
+        let expected = Framebuffer::from_bytes(IMAGE, ImageFormat::Bmp).expect("decode");
+        assert_eq!(index, 0);
+        assert_eq!(frame.pixels(), expected.pixels());
+
+        cancel.store(true, Ordering::Relaxed);
+        handle.join().expect("downloader");
+    }
+
+    #[test]
+    fn a_failing_source_does_not_hold_up_the_others() {
+        let url = serve();
+        let sources = vec![
+            format!("{}/missing.png", url),
+            "http://127.0.0.1:1/unreachable.png".to_string(),
+            format!("{}/sakura.bmp", url),
+        ];
+        let (tx, rx) = channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let handle = spawn(&config(sources), tx, Metrics::shared(), Arc::clone(&cancel));
+
+        let (index, _) = rx.recv_timeout(Duration::from_secs(5)).expect("a download");
+        assert_eq!(index, 2);
+        // The others are retried later, not now
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_err());
+
+        cancel.store(true, Ordering::Relaxed);
+        handle.join().expect("downloader");
+    }
+
+    #[test]
+    fn unchanged_images_are_not_downloaded_again() {
+        let url = format!("{}/sakura.bmp", serve());
+
+        let validators = match fetch(&url, &Validators::default(), TIMEOUT).expect("fetch") {
+            Fetched::Changed(_, validators) => validators,
+            Fetched::Unchanged => panic!("nothing to compare with yet"),
+        };
+        assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+
+        let fetched = fetch(&url, &validators, TIMEOUT).expect("fetch");
+        assert!(matches!(fetched, Fetched::Unchanged));
+    }
+}
@@ -0,0 +1,47 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// What can go wrong while the display is running. None of it is worth taking
+// the whole display down for, the caller decides what to give up on instead.
+#[derive(Debug)]
+pub enum Error {
+    Image(String), // a background that does not decode
+    Maze(String),  // the generator could not build a maze
+    Format(fmt::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Image(e) => write!(f, "bad image: {}", e),
+            Error::Maze(e) => write!(f, "cannot generate maze: {}", e),
+            Error::Format(e) => write!(f, "cannot format text: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<fmt::Error> for Error {
+    fn from(e: fmt::Error) -> Self {
+        Error::Format(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+// Drawing on the canvas cannot fail, this lets `?` be used on it anyway
+impl From<Infallible> for Error {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
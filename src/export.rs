@@ -0,0 +1,223 @@
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+
+use crate::config::Config;
+use crate::sensors::Readings;
+use crate::storage::{stored_metrics, Storage};
+
+const USAGE: &str = "usage: leddy export [--from DATE] [--to DATE] [--format csv|json]
+
+DATE is either YYYY-MM-DD, a whole day in UTC, or an RFC 3339 timestamp. A
+plain --to date is inclusive. Without --from everything stored is exported.";
+
+enum Format {
+    Csv,
+    Json,
+}
+
+// `leddy export`, writes stored readings to stdout
+pub fn run(config: &Config, args: &[String]) -> Result<(), String> {
+    let mut from = DateTime::<Utc>::MIN_UTC;
+    let mut to = Utc::now();
+    let mut format = Format::Csv;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value\n\n{}", arg, USAGE));
+
+        match arg.as_str() {
+            "--from" => from = parse_date(value()?, false)?,
+            "--to" => to = parse_date(value()?, true)?,
+            "--format" => {
+                format = match value()?.as_str() {
+                    "csv" => Format::Csv,
+                    "json" => Format::Json,
+                    other => return Err(format!("unknown format `{}`\n\n{}", other, USAGE)),
+                }
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            other => return Err(format!("unknown argument `{}`\n\n{}", other, USAGE)),
+        }
+    }
+
+    let storage = Storage::open(&config.storage).map_err(|e| e.to_string())?;
+    let stdout = io::stdout();
+    export(&storage, from, to, &format, &mut stdout.lock()).map_err(|e| e.to_string())
+}
+
+fn export(
+    storage: &Storage,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    format: &Format,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut first = true;
+
+    let mut result = match format {
+        Format::Csv => {
+            let names: Vec<&str> = stored_metrics().map(|m| m.name()).collect();
+            writeln!(out, "time,{}", names.join(","))
+        }
+        Format::Json => write!(out, "["),
+    };
+
+    storage.read_range(from, to, |at, readings| {
+        if result.is_err() {
+            return; // stdout went away, nothing more to do
+        }
+
+        result = match format {
+            Format::Csv => {
+                let mut row = at.to_rfc3339();
+                for metric in stored_metrics() {
+                    row.push(',');
+                    if let Some(value) = readings.get(metric) {
+                        row.push_str(&value.to_string());
+                    }
+                }
+                writeln!(out, "{}", row)
+            }
+            Format::Json => {
+                let separator = if first { "\n" } else { ",\n" };
+                first = false;
+                write!(out, "{}{}", separator, row_json(at, readings))
+            }
+        };
+    })?;
+
+    if let Format::Json = format {
+        result = result.and_then(|_| writeln!(out, "\n]"));
+    }
+
+    result
+}
+
+// One row as a JSON object, e.g. {"time": "2022-05-01T12:00:00+00:00", "co2": 812.0}
+pub fn row_json(at: DateTime<Utc>, readings: &Readings) -> Value {
+    let mut row = Map::new();
+    row.insert("time".to_string(), Value::from(at.to_rfc3339()));
+    for (metric, value) in readings.iter() {
+        row.insert(metric.name().to_string(), Value::from(value));
+    }
+    Value::Object(row)
+}
+
+fn parse_date(s: &str, end_of_day: bool) -> Result<DateTime<Utc>, String> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(s) {
+        return Ok(at.with_timezone(&Utc));
+    }
+
+    match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) if end_of_day => Ok(date.and_time(NaiveTime::MIN).and_utc() + Duration::days(1)),
+        Ok(date) => Ok(date.and_time(NaiveTime::MIN).and_utc()),
+        Err(_) => Err(format!("invalid date `{}`\n\n{}", s, USAGE)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::sensors::Metric;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    // A row a minute either side of both ends of May 1st
+    fn storage(dir: &tempfile::TempDir) -> Storage {
+        let mut storage = Storage::open(&StorageConfig {
+            enabled: true,
+            dir: dir.path().to_str().expect("utf-8 path").to_string(),
+            retention_days: 0,
+        })
+        .expect("open storage");
+
+        let rows = [
+            ("2022-04-30T23:59:00Z", 600.0),
+            ("2022-05-01T00:00:00Z", 610.0),
+            ("2022-05-01T23:59:00Z", 620.0),
+            ("2022-05-02T00:00:00Z", 630.0),
+        ];
+        for (time, co2) in rows {
+            let mut readings = Readings::default();
+            readings.set(Metric::Co2, co2);
+            storage.append(at(time), &readings).unwrap();
+        }
+        storage.flush().unwrap();
+        storage
+    }
+
+    fn export_to_string(storage: &Storage, from: &str, to: &str, format: Format) -> String {
+        let from = parse_date(from, false).unwrap();
+        let to = parse_date(to, true).unwrap();
+        let mut out = Vec::new();
+        export(storage, from, to, &format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_covers_whole_days() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = export_to_string(&storage(&dir), "2022-05-01", "2022-05-01", Format::Csv);
+
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("time,"));
+        assert!(lines[1].starts_with("2022-05-01T00:00:00+00:00,"));
+        assert!(lines[2].starts_with("2022-05-01T23:59:00+00:00,"));
+        assert!(lines[1].contains(",610"));
+    }
+
+    #[test]
+    fn json_covers_whole_days() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = export_to_string(&storage(&dir), "2022-05-01", "2022-05-01", Format::Json);
+
+        let rows: Vec<Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["time"], "2022-05-01T00:00:00+00:00");
+        assert_eq!(rows[0]["co2"], 610.0);
+        assert_eq!(rows[1]["co2"], 620.0);
+    }
+
+    #[test]
+    fn timestamps_are_exact() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = export_to_string(
+            &storage(&dir),
+            "2022-04-30T23:59:00Z",
+            "2022-05-01T23:59:00Z",
+            Format::Json,
+        );
+
+        // --from is inclusive, a --to timestamp is not
+        let rows: Vec<Value> = serde_json::from_str(&json).unwrap();
+        let co2: Vec<Option<f64>> = rows.iter().map(|row| row["co2"].as_f64()).collect();
+        assert_eq!(co2, [Some(600.0), Some(610.0)]);
+    }
+
+    #[test]
+    fn empty_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = export_to_string(&storage(&dir), "2022-06-01", "2022-06-30", Format::Json);
+        assert_eq!(serde_json::from_str::<Vec<Value>>(&json).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("2022-05-01", false), Ok(at("2022-05-01T00:00:00Z")));
+        assert_eq!(parse_date("2022-05-01", true), Ok(at("2022-05-02T00:00:00Z")));
+
+        // Offsets are kept, and a timestamp --to is not moved to the end of the day
+        assert_eq!(parse_date("2022-05-01T02:00:00+02:00", true), Ok(at("2022-05-01T00:00:00Z")));
+
+        assert!(parse_date("2022-05-32", false).is_err());
+        assert!(parse_date("yesterday", false).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::{Exporter, Sample};
+use crate::config::InfluxConfig;
+
+// Writes batches to InfluxDB's HTTP API in line protocol, e.g.
+// `leddy,room=office co2=812,temperature=21.5 1651406400`
+pub struct Influx {
+    url: String,
+    token: Option<String>,
+    measurement: String, // escaped
+    tags: String,        // escaped, with a leading comma
+}
+
+impl Influx {
+    pub fn new(config: &InfluxConfig) -> Self {
+        Influx {
+            url: config.url.clone(),
+            token: config.token.clone(),
+            measurement: escape(&config.measurement, &[',', ' ']),
+            tags: tags(&config.tags),
+        }
+    }
+
+    fn lines(&self, batch: &[Sample]) -> String {
+        let mut body = String::new();
+
+        for (at, readings) in batch {
+            let fields: Vec<String> = readings
+                .iter()
+                .map(|(metric, value)| format!("{}={}", metric.name(), value))
+                .collect();
+            if fields.is_empty() {
+                continue;
+            }
+
+            writeln!(
+                body,
+                "{}{} {} {}",
+                self.measurement,
+                self.tags,
+                fields.join(","),
+                at.timestamp()
+            )
+            .expect("write to string");
+        }
+
+        body
+    }
+}
+
+impl Exporter for Influx {
+    fn name(&self) -> &'static str {
+        "InfluxDB"
+    }
+
+    fn send(&self, batch: &[Sample]) -> Result<(), String> {
+        let mut request = minreq::post(&self.url)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(self.lines(batch))
+            .with_timeout(30);
+        if let Some(token) = &self.token {
+            request = request.with_header("Authorization", format!("Token {}", token));
+        }
+
+        let response = request.send().map_err(|e| e.to_string())?;
+        match response.status_code {
+            200..=299 => Ok(()),
+            code => Err(format!("{} {}", code, response.as_str().unwrap_or_default().trim())),
+        }
+    }
+}
+
+fn tags(tags: &BTreeMap<String, String>) -> String {
+    let special = [',', '=', ' '];
+    tags.iter()
+        .map(|(k, v)| format!(",{}={}", escape(k, &special), escape(v, &special)))
+        .collect()
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::{Metric, Readings};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn special_characters_are_escaped() {
+        assert_eq!(escape("living room,1", &[',', ' ']), "living\\ room\\,1");
+        assert_eq!(escape("a=b", &[',', ' ']), "a=b");
+
+        let mut config = BTreeMap::new();
+        config.insert("room".to_string(), "big office".to_string());
+        config.insert("k=v".to_string(), "a,b".to_string());
+        assert_eq!(tags(&config), ",k\\=v=a\\,b,room=big\\ office");
+    }
+
+    #[test]
+    fn lines() {
+        let mut tags = BTreeMap::new();
+        tags.insert("room".to_string(), "big office".to_string());
+        let influx = Influx::new(&InfluxConfig {
+            measurement: "my leddy".to_string(),
+            tags,
+            ..InfluxConfig::default()
+        });
+
+        let mut readings = Readings::default();
+        readings.set(Metric::Co2, 812.0);
+        readings.set(Metric::Temperature, 21.5);
+        let at = Utc.with_ymd_and_hms(2022, 5, 1, 12, 0, 0).unwrap();
+
+        // Samples without readings have no fields, so no line
+        let batch = [(at, readings), (at, Readings::default())];
+        assert_eq!(
+            influx.lines(&batch),
+            "my\\ leddy,room=big\\ office co2=812,temperature=21.5 1651406400\n"
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::export::row_json;
+use crate::sensors::{Metric, Readings};
+
+mod influx;
+mod webhook;
+
+use influx::Influx;
+use webhook::Webhook;
+
+// Wait this long after a failed push, doubled while it keeps failing
+const RETRY_MIN: Duration = Duration::from_secs(10);
+const RETRY_MAX: Duration = Duration::from_secs(10 * 60);
+
+pub type Sample = (DateTime<Utc>, Readings);
+
+// Something that takes batches of readings somewhere else
+pub trait Exporter: Send {
+    fn name(&self) -> &'static str;
+    // Pushes a batch, oldest first. Only Ok once the other end has accepted it.
+    fn send(&self, batch: &[Sample]) -> Result<(), String>;
+}
+
+// How readings are collected before they are pushed
+struct Batching {
+    batch_size: usize,
+    flush_interval: Duration,
+    queue_limit: usize,
+}
+
+// Starts a thread for every enabled exporter, returns the senders to hand to the sensor pipeline
+pub fn spawn(config: &Config, cancel: &Arc<AtomicBool>) -> Vec<(Sender<Sample>, JoinHandle<()>)> {
+    let mut exporters: Vec<(Box<dyn Exporter>, Batching)> = Vec::new();
+    if config.influxdb.enabled {
+        let c = &config.influxdb;
+        let batching = Batching::new(c.batch_size, c.flush_interval, c.queue_limit);
+        exporters.push((Box::new(Influx::new(c)), batching));
+    }
+    if config.webhook.enabled {
+        let c = &config.webhook;
+        let batching = Batching::new(c.batch_size, c.flush_interval, c.queue_limit);
+        exporters.push((Box::new(Webhook::new(c)), batching));
+    }
+
+    let mut workers = Vec::new();
+    for (exporter, batching) in exporters {
+        let (tx, rx) = mpsc::channel();
+        let queue = Queue::new(&config.storage.dir, exporter.name(), batching.queue_limit);
+        let mut worker = Worker::new(exporter, batching, queue);
+        let cancel = Arc::clone(cancel);
+        workers.push((tx, thread::spawn(move || worker.run(rx, &cancel))));
+    }
+
+    workers
+}
+
+impl Batching {
+    fn new(batch_size: usize, flush_interval: u64, queue_limit: usize) -> Self {
+        Batching {
+            batch_size: batch_size.max(1),
+            flush_interval: Duration::from_secs(flush_interval),
+            queue_limit,
+        }
+    }
+}
+
+struct Worker {
+    exporter: Box<dyn Exporter>,
+    batching: Batching,
+    queue: Queue,
+    backoff: Duration,
+    next_attempt: Instant,
+    last_flush: Instant,
+}
+
+impl Worker {
+    fn new(exporter: Box<dyn Exporter>, batching: Batching, queue: Queue) -> Self {
+        Worker {
+            exporter,
+            batching,
+            queue,
+            backoff: RETRY_MIN,
+            next_attempt: Instant::now(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    fn run(&mut self, rx: Receiver<Sample>, cancel: &AtomicBool) {
+        while !cancel.load(Ordering::Relaxed) {
+            match rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(sample) => self.queue.push(sample),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            self.export();
+        }
+
+        // Whatever did not make it out is picked up again on the next start
+        self.save();
+    }
+
+    // Pushes the queue once a batch is full or the flush interval is up,
+    // unless we are still backing off after a failure
+    fn export(&mut self) {
+        let due = self.queue.len() >= self.batching.batch_size
+            || self.last_flush.elapsed() >= self.batching.flush_interval;
+        if self.queue.is_empty() || !due || Instant::now() < self.next_attempt {
+            return;
+        }
+
+        self.last_flush = Instant::now();
+        match self.flush() {
+            Ok(()) => self.backoff = RETRY_MIN,
+            Err(e) => {
+                warn!(
+                    "{} export failed, {} readings queued: {}",
+                    self.exporter.name(),
+                    self.queue.len(),
+                    e
+                );
+                self.next_attempt = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(RETRY_MAX);
+            }
+        }
+
+        self.save();
+    }
+
+    fn save(&mut self) {
+        if let Err(e) = self.queue.save() {
+            error!("Could not save {} export queue: {}", self.exporter.name(), e);
+        }
+    }
+
+    // Sends everything queued, a batch at a time
+    fn flush(&mut self) -> Result<(), String> {
+        while !self.queue.is_empty() {
+            let size = self.batching.batch_size.min(self.queue.len());
+            let batch: Vec<Sample> = self.queue.samples.iter().take(size).cloned().collect();
+            self.exporter.send(&batch)?;
+            self.queue.drain(size);
+        }
+        Ok(())
+    }
+}
+
+// Readings waiting to be exported. Kept on disk while there is a backlog, so a
+// restart or power cut during an outage does not lose them. New readings are
+// appended to the file, it is only rewritten once some have left the queue.
+struct Queue {
+    path: PathBuf,
+    limit: usize,
+    samples: VecDeque<Sample>,
+    saved: usize, // oldest samples that are in the file already
+    stale: bool,  // the file has lines that are no longer queued
+}
+
+impl Queue {
+    fn new(dir: &str, name: &str, limit: usize) -> Self {
+        let path = PathBuf::from(dir).join(format!("queue-{}.jsonl", name.to_lowercase()));
+        let mut queue = Queue {
+            path,
+            limit,
+            samples: VecDeque::new(),
+            saved: 0,
+            stale: false,
+        };
+
+        match queue.load() {
+            Ok(0) => {}
+            Ok(n) => info!("{} readings left to export to {}", n, name),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => error!("Could not load {} export queue: {}", name, e),
+        }
+
+        queue
+    }
+
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    // Drops the oldest readings once the queue is full
+    fn push(&mut self, sample: Sample) {
+        if self.samples.len() >= self.limit {
+            self.samples.pop_front();
+            self.forget(1);
+        }
+        self.samples.push_back(sample);
+    }
+
+    fn drain(&mut self, n: usize) {
+        self.samples.drain(..n);
+        self.forget(n);
+    }
+
+    // The oldest `n` samples are gone, the file still has them if they were saved
+    fn forget(&mut self, n: usize) {
+        let saved = n.min(self.saved);
+        self.saved -= saved;
+        self.stale |= saved > 0;
+    }
+
+    fn load(&mut self) -> io::Result<usize> {
+        let file = fs::File::open(&self.path)?;
+        let mut lines = 0;
+        for line in BufReader::new(file).lines() {
+            lines += 1;
+            if let Some(sample) = parse_row(&line?) {
+                self.push(sample);
+            }
+        }
+
+        self.saved = self.samples.len();
+        self.stale = lines > self.saved;
+        Ok(self.samples.len())
+    }
+
+    // Brings the file up to date with the backlog, or removes it once there is none
+    fn save(&mut self) -> io::Result<()> {
+        if self.samples.is_empty() {
+            if self.path.exists() {
+                fs::remove_file(&self.path)?;
+            }
+            self.saved = 0;
+            self.stale = false;
+            return Ok(());
+        }
+
+        if self.stale {
+            self.rewrite()?;
+        } else if self.saved < self.samples.len() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+
+            let file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+            let mut file = io::BufWriter::new(file);
+            for (at, readings) in self.samples.range(self.saved..) {
+                writeln!(file, "{}", row_json(*at, readings))?;
+            }
+            file.into_inner().map_err(|e| e.into_error())?.sync_data()?;
+        }
+
+        self.saved = self.samples.len();
+        self.stale = false;
+        Ok(())
+    }
+
+    // Write next to it and rename, so a crash mid-write keeps the old queue
+    fn rewrite(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let tmp = self.path.with_extension("jsonl.tmp");
+        let mut file = io::BufWriter::new(fs::File::create(&tmp)?);
+        for (at, readings) in &self.samples {
+            writeln!(file, "{}", row_json(*at, readings))?;
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+// The reverse of `row_json`
+fn parse_row(line: &str) -> Option<Sample> {
+    let row: Value = serde_json::from_str(line).ok()?;
+    let row = row.as_object()?;
+
+    let at = DateTime::parse_from_rfc3339(row.get("time")?.as_str()?).ok()?;
+    let mut readings = Readings::default();
+    for (name, value) in row {
+        if let (Some(metric), Some(value)) = (Metric::from_name(name), value.as_f64()) {
+            readings.set(metric, value as f32);
+        }
+    }
+
+    Some((at.with_timezone(&Utc), readings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::InfluxConfig;
+    use chrono::TimeZone;
+    use std::io::Read;
+    use tiny_http::{Response, Server, StatusCode};
+
+    fn sample(minute: u32, co2: f32) -> Sample {
+        let mut readings = Readings::default();
+        readings.set(Metric::Co2, co2);
+        readings.set(Metric::Temperature, 21.5);
+        (Utc.with_ymd_and_hms(2022, 5, 1, 12, minute, 0).unwrap(), readings)
+    }
+
+    fn co2s(queue: &Queue) -> Vec<f32> {
+        queue.samples.iter().filter_map(|(_, r)| r.get(Metric::Co2)).collect()
+    }
+
+    fn lines(queue: &Queue) -> usize {
+        fs::read_to_string(&queue.path).map(|s| s.lines().count()).unwrap_or(0)
+    }
+
+    #[test]
+    fn parse_row_reverses_row_json() {
+        let (at, readings) = sample(3, 812.0);
+        let (parsed_at, parsed) = parse_row(&row_json(at, &readings).to_string()).expect("a row");
+        assert_eq!(parsed_at, at);
+        assert_eq!(parsed.iter().collect::<Vec<_>>(), readings.iter().collect::<Vec<_>>());
+
+        assert!(parse_row("not json").is_none());
+        assert!(parse_row(r#"{"co2": 812}"#).is_none());
+    }
+
+    #[test]
+    fn queue_survives_a_restart() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let dir = dir.path().to_str().expect("utf-8 path");
+
+        let mut queue = Queue::new(dir, "Test", 10);
+        for minute in 0..3 {
+            queue.push(sample(minute, 600.0 + minute as f32));
+        }
+        queue.save().expect("save");
+
+        let mut loaded = Queue::new(dir, "Test", 10);
+        assert_eq!(co2s(&loaded), [600.0, 601.0, 602.0]);
+        assert_eq!(loaded.samples[2].0, sample(2, 0.0).0);
+
+        // Down to nothing removes the file
+        loaded.drain(3);
+        loaded.save().expect("save");
+        assert!(!loaded.path.exists());
+    }
+
+    #[test]
+    fn queue_appends_until_readings_leave_it() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let mut queue = Queue::new(dir.path().to_str().expect("utf-8 path"), "Test", 3);
+
+        queue.push(sample(0, 600.0));
+        queue.push(sample(1, 601.0));
+        queue.save().expect("save");
+        queue.push(sample(2, 602.0));
+        queue.save().expect("save");
+        assert_eq!(lines(&queue), 3);
+        assert!(!queue.stale);
+
+        // Over the limit, the oldest has to go from the file too
+        queue.push(sample(3, 603.0));
+        assert!(queue.stale);
+        queue.save().expect("save");
+        assert_eq!(lines(&queue), 3);
+
+        queue.drain(1);
+        queue.save().expect("save");
+        assert_eq!(lines(&queue), 2);
+        assert_eq!(co2s(&Queue::new(dir.path().to_str().unwrap(), "Test", 3)), [602.0, 603.0]);
+    }
+
+    #[test]
+    fn failed_exports_are_retried_with_backoff() {
+        let server = Server::http("127.0.0.1:0").expect("listen");
+        let port = server.server_addr().to_ip().expect("an IP address").port();
+        let (bodies, received) = mpsc::channel();
+        let responder = thread::spawn(move || {
+            for status in [503, 204] {
+                let mut request = server.recv().expect("a request");
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).expect("read body");
+                bodies.send(body).expect("send body");
+                request.respond(Response::empty(StatusCode(status))).expect("respond");
+            }
+        });
+
+        let dir = tempfile::tempdir().expect("temp dir");
+        let influx = Influx::new(&InfluxConfig {
+            url: format!("http://127.0.0.1:{}/api/v2/write", port),
+            ..InfluxConfig::default()
+        });
+        let queue = Queue::new(dir.path().to_str().expect("utf-8 path"), "Test", 10);
+        let mut worker = Worker::new(Box::new(influx), Batching::new(1, 60, 10), queue);
+
+        worker.queue.push(sample(0, 812.0));
+        worker.export();
+        assert_eq!(received.try_recv().as_deref(), Ok("leddy co2=812,temperature=21.5 1651406400\n"));
+        assert_eq!(co2s(&worker.queue), [812.0]);
+        assert_eq!(lines(&worker.queue), 1);
+        assert_eq!(worker.backoff, RETRY_MIN * 2);
+
+        // Nothing is sent while backing off
+        worker.export();
+        assert!(received.try_recv().is_err());
+
+        worker.next_attempt = Instant::now();
+        worker.export();
+        assert!(received.try_recv().is_ok());
+        assert!(worker.queue.is_empty());
+        assert!(!worker.queue.path.exists());
+        assert_eq!(worker.backoff, RETRY_MIN);
+
+        responder.join().expect("responder");
+    }
+}
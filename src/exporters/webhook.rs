@@ -0,0 +1,45 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use super::{Exporter, Sample};
+use crate::config::WebhookConfig;
+use crate::export::row_json;
+
+// POSTs batches as a JSON array of rows, the same as `leddy export --format json`
+pub struct Webhook {
+    url: String,
+    headers: BTreeMap<String, String>,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig) -> Self {
+        Webhook {
+            url: config.url.clone(),
+            headers: config.headers.clone(),
+        }
+    }
+}
+
+impl Exporter for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn send(&self, batch: &[Sample]) -> Result<(), String> {
+        let rows: Vec<Value> = batch.iter().map(|(at, readings)| row_json(*at, readings)).collect();
+
+        let mut request = minreq::post(&self.url)
+            .with_header("Content-Type", "application/json")
+            .with_body(Value::Array(rows).to_string())
+            .with_timeout(30);
+        for (name, value) in &self.headers {
+            request = request.with_header(name, value);
+        }
+
+        let response = request.send().map_err(|e| e.to_string())?;
+        match response.status_code {
+            200..=299 => Ok(()),
+            code => Err(format!("{} {}", code, response.as_str().unwrap_or_default().trim())),
+        }
+    }
+}
@@ -0,0 +1,503 @@
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::sensors::{Metric, Readings};
+
+pub type SharedHistory = Arc<Mutex<History>>;
+
+// A reading older than this is no longer current, the sensor has likely gone away
+pub const STALE_AFTER: Duration = Duration::from_secs(30);
+
+// Too few readings or too short a span and the slope is meaningless
+const TREND_MIN_SAMPLES: usize = 5;
+const TREND_MIN_SPAN: f32 = 2.0; // minutes
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Raw,         // every reading, kept for an hour
+    FiveMinutes, // kept for a day
+    Hourly,      // kept for 30 days
+}
+
+impl Resolution {
+    fn bucket(&self) -> i64 {
+        match self {
+            Resolution::Raw => 0,
+            Resolution::FiveMinutes => 5 * 60,
+            Resolution::Hourly => 60 * 60,
+        }
+    }
+
+    fn retention(&self) -> i64 {
+        match self {
+            Resolution::Raw => 60 * 60,
+            Resolution::FiveMinutes => 24 * 60 * 60,
+            Resolution::Hourly => 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+// A single reading is an aggregate of one, so every resolution returns these
+#[derive(Debug, Clone, Copy)]
+pub struct Aggregate {
+    pub start: DateTime<Utc>,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub count: u32,
+}
+
+impl Aggregate {
+    fn new(start: DateTime<Utc>, value: f32) -> Self {
+        Aggregate {
+            start,
+            min: value,
+            max: value,
+            mean: value,
+            count: 1,
+        }
+    }
+
+    fn add(&mut self, value: f32) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.count += 1;
+        self.mean += (value - self.mean) / self.count as f32;
+    }
+
+    fn merge(&mut self, other: &Aggregate) {
+        let count = self.count + other.count;
+        self.mean = (self.mean * self.count as f32 + other.mean * other.count as f32) / count as f32;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.start = self.start.min(other.start);
+        self.count = count;
+    }
+}
+
+struct Ring {
+    resolution: Resolution,
+    entries: VecDeque<Aggregate>,
+}
+
+impl Ring {
+    fn new(resolution: Resolution) -> Self {
+        Ring {
+            resolution,
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn add(&mut self, at: DateTime<Utc>, value: f32) {
+        let bucket = self.resolution.bucket();
+
+        if bucket == 0 {
+            self.entries.push_back(Aggregate::new(at, value));
+        } else {
+            let ts = at.timestamp();
+            // Never out of range, it is a little before `at`
+            let start = DateTime::from_timestamp(ts - ts.rem_euclid(bucket), 0).unwrap_or(at);
+
+            match self.entries.back_mut() {
+                Some(last) if last.start == start => last.add(value),
+                _ => self.entries.push_back(Aggregate::new(start, value)),
+            }
+        }
+
+        self.prune(at);
+    }
+
+    fn prune(&mut self, now: DateTime<Utc>) {
+        let oldest = now.timestamp() - self.resolution.retention();
+        while matches!(self.entries.front(), Some(e) if e.start.timestamp() < oldest) {
+            self.entries.pop_front();
+        }
+    }
+
+    // Puts older entries in front, a bucket both have is merged
+    fn prepend(&mut self, mut older: VecDeque<Aggregate>) {
+        if let Some(first) = self.entries.front_mut() {
+            older.retain(|e| e.start <= first.start);
+            if matches!(older.back(), Some(last) if last.start == first.start) {
+                let last = older.pop_back().expect("just matched");
+                first.merge(&last);
+            }
+        }
+
+        older.append(&mut self.entries);
+        self.entries = older;
+        if let Some(newest) = self.entries.back().map(|e| e.start) {
+            self.prune(newest);
+        }
+    }
+}
+
+struct Series {
+    rings: [Ring; 3],
+}
+
+impl Series {
+    fn new() -> Self {
+        Series {
+            rings: [
+                Ring::new(Resolution::Raw),
+                Ring::new(Resolution::FiveMinutes),
+                Ring::new(Resolution::Hourly),
+            ],
+        }
+    }
+
+    fn ring(&self, resolution: Resolution) -> &Ring {
+        self.rings
+            .iter()
+            .find(|r| r.resolution == resolution)
+            .expect("every resolution has a ring")
+    }
+}
+
+// Recent readings of every metric, downsampled as they get older
+#[derive(Default)]
+pub struct History {
+    series: BTreeMap<Metric, Series>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shared() -> SharedHistory {
+        Arc::new(Mutex::new(Self::new()))
+    }
+
+    pub fn record(&mut self, at: DateTime<Utc>, readings: &Readings) {
+        for (metric, value) in readings.iter() {
+            self.add(metric, at, value);
+        }
+    }
+
+    pub fn add(&mut self, metric: Metric, at: DateTime<Utc>, value: f32) {
+        let series = self.series.entry(metric).or_insert_with(Series::new);
+        for ring in &mut series.rings {
+            ring.add(at, value);
+        }
+    }
+
+    // Adds readings from before the ones we have, e.g. reloaded from storage
+    pub fn prepend(&mut self, older: History) {
+        for (metric, older) in older.series {
+            let series = self.series.entry(metric).or_insert_with(Series::new);
+            for (ring, older) in series.rings.iter_mut().zip(older.rings) {
+                ring.prepend(older.entries);
+            }
+        }
+    }
+
+    // Everything within `window` from now, oldest first. Asking for more than
+    // the resolution keeps just returns what there is.
+    pub fn series(&self, metric: Metric, window: Duration, resolution: Resolution) -> Vec<Aggregate> {
+        let since = Utc::now().timestamp() - window.as_secs() as i64;
+
+        match self.series.get(&metric) {
+            Some(series) => series
+                .ring(resolution)
+                .entries
+                .iter()
+                .filter(|e| e.start.timestamp() >= since - resolution.bucket())
+                .copied()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn latest(&self, metric: Metric) -> Option<Aggregate> {
+        self.series
+            .get(&metric)
+            .and_then(|s| s.ring(Resolution::Raw).entries.back().copied())
+    }
+
+    pub fn trend(&self, metric: Metric, window: Duration) -> Option<Trend> {
+        let samples = self.series(metric, window, Resolution::Raw);
+        let first = samples.first()?.start;
+        let latest = samples.last()?;
+
+        let points: Vec<(f32, f32)> = samples
+            .iter()
+            .map(|s| ((s.start - first).num_milliseconds() as f32 / 60_000.0, s.mean))
+            .collect();
+        let span = points.last()?.0;
+        if points.len() < TREND_MIN_SAMPLES || span < TREND_MIN_SPAN {
+            return None;
+        }
+
+        let n = points.len() as f32;
+        let mean_t = points.iter().map(|p| p.0).sum::<f32>() / n;
+        let mean_v = points.iter().map(|p| p.1).sum::<f32>() / n;
+        let covariance: f32 = points.iter().map(|(t, v)| (t - mean_t) * (v - mean_v)).sum();
+        let variance: f32 = points.iter().map(|(t, _)| (t - mean_t).powi(2)).sum();
+
+        Some(Trend {
+            metric,
+            per_minute: covariance / variance,
+            latest: latest.mean,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Rising,
+    Falling,
+    Steady,
+}
+
+// Least squares fit over the recent raw readings of a metric
+#[derive(Debug, Clone, Copy)]
+pub struct Trend {
+    pub metric: Metric,
+    pub per_minute: f32,
+    pub latest: f32,
+}
+
+// Changes slower than this per minute are considered noise
+fn steady_rate(metric: Metric) -> f32 {
+    match metric {
+        Metric::Co2 | Metric::ECo2 | Metric::Tvoc => 5.0,
+        Metric::Temperature | Metric::Pressure => 0.05,
+        Metric::DewPoint | Metric::HeatIndex | Metric::Humidex => 0.05,
+        Metric::Humidity => 0.2,
+        Metric::AbsoluteHumidity => 0.05,
+        Metric::Light => 10.0,
+        Metric::Comfort => f32::INFINITY,
+    }
+}
+
+impl Trend {
+    pub fn direction(&self) -> Direction {
+        if self.per_minute.abs() < steady_rate(self.metric) {
+            Direction::Steady
+        } else if self.per_minute > 0.0 {
+            Direction::Rising
+        } else {
+            Direction::Falling
+        }
+    }
+
+    // Minutes until the metric reaches `target` at the current rate, if it is heading there
+    pub fn minutes_until(&self, target: f32) -> Option<f32> {
+        let minutes = (target - self.latest) / self.per_minute;
+        if self.direction() != Direction::Steady && minutes > 0.0 {
+            Some(minutes)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes_ago(minutes: i64) -> DateTime<Utc> {
+        Utc::now() - chrono::Duration::minutes(minutes)
+    }
+
+    #[test]
+    fn prepend_puts_older_readings_first() {
+        let mut live = History::new();
+        live.add(Metric::Co2, minutes_ago(1), 600.0);
+
+        let mut older = History::new();
+        older.add(Metric::Co2, minutes_ago(30), 500.0);
+        older.add(Metric::Co2, minutes_ago(20), 550.0);
+        older.add(Metric::Temperature, minutes_ago(20), 21.0);
+        live.prepend(older);
+
+        let raw = live.series(Metric::Co2, Duration::from_secs(60 * 60), Resolution::Raw);
+        let means: Vec<f32> = raw.iter().map(|a| a.mean).collect();
+        assert_eq!(means, [500.0, 550.0, 600.0]);
+        assert_eq!(live.latest(Metric::Co2).map(|a| a.mean), Some(600.0));
+        assert_eq!(live.latest(Metric::Temperature).map(|a| a.mean), Some(21.0));
+    }
+
+    #[test]
+    fn prepend_merges_a_shared_bucket() {
+        let now = Utc::now().timestamp();
+        let hour = DateTime::from_timestamp(now - now.rem_euclid(60 * 60), 0).unwrap();
+
+        let mut live = History::new();
+        live.add(Metric::Co2, hour + chrono::Duration::minutes(1), 600.0);
+
+        let mut older = History::new();
+        older.add(Metric::Co2, hour - chrono::Duration::hours(1), 300.0);
+        older.add(Metric::Co2, hour, 400.0);
+        live.prepend(older);
+
+        let hourly = live.series(Metric::Co2, Duration::from_secs(2 * 24 * 60 * 60), Resolution::Hourly);
+        assert_eq!(hourly.len(), 2);
+        assert_eq!((hourly[0].start, hourly[0].mean), (hour - chrono::Duration::hours(1), 300.0));
+        assert_eq!((hourly[1].start, hourly[1].count), (hour, 2));
+        assert_eq!((hourly[1].min, hourly[1].max, hourly[1].mean), (400.0, 600.0, 500.0));
+    }
+
+    #[test]
+    fn readings_are_aggregated() {
+        let now = Utc::now().timestamp();
+        let hour = DateTime::from_timestamp(now - now.rem_euclid(60 * 60) - 60 * 60, 0).unwrap();
+
+        let mut history = History::new();
+        for (minute, value) in [(0, 10.0), (1, 20.0), (4, 30.0), (5, 40.0)] {
+            history.add(Metric::Co2, hour + chrono::Duration::minutes(minute), value);
+        }
+        let window = Duration::from_secs(3 * 60 * 60);
+
+        let raw = history.series(Metric::Co2, window, Resolution::Raw);
+        assert_eq!(raw.len(), 4);
+
+        let five = history.series(Metric::Co2, window, Resolution::FiveMinutes);
+        assert_eq!(five.len(), 2);
+        assert_eq!((five[0].start, five[0].count), (hour, 3));
+        assert_eq!((five[0].min, five[0].max, five[0].mean), (10.0, 30.0, 20.0));
+        assert_eq!((five[1].start, five[1].mean), (hour + chrono::Duration::minutes(5), 40.0));
+
+        let hourly = history.series(Metric::Co2, window, Resolution::Hourly);
+        assert_eq!(hourly.len(), 1);
+        assert_eq!((hourly[0].start, hourly[0].count), (hour, 4));
+        assert_eq!((hourly[0].min, hourly[0].max, hourly[0].mean), (10.0, 40.0, 25.0));
+    }
+
+    #[test]
+    fn rings_keep_their_retention() {
+        let start = DateTime::from_timestamp(1_651_363_200, 0).unwrap(); // on the hour
+        let resolutions = [
+            (Resolution::Raw, chrono::Duration::hours(1)),
+            (Resolution::FiveMinutes, chrono::Duration::days(1)),
+            (Resolution::Hourly, chrono::Duration::days(30)),
+        ];
+
+        for (resolution, retention) in resolutions {
+            let mut ring = Ring::new(resolution);
+            ring.add(start, 1.0);
+
+            ring.prune(start + retention);
+            assert_eq!(ring.entries.len(), 1, "{:?}", resolution);
+            ring.prune(start + retention + chrono::Duration::seconds(1));
+            assert!(ring.entries.is_empty(), "{:?}", resolution);
+        }
+    }
+
+    #[test]
+    fn adding_prunes() {
+        let start = DateTime::from_timestamp(1_651_363_200, 0).unwrap();
+        let mut history = History::new();
+        history.add(Metric::Co2, start, 500.0);
+        history.add(Metric::Co2, start + chrono::Duration::hours(2), 600.0);
+
+        // Raw readings are kept for an hour, the rest longer
+        let rings = &history.series[&Metric::Co2].rings;
+        let lengths: Vec<usize> = rings.iter().map(|r| r.entries.len()).collect();
+        assert_eq!(lengths, [1, 2, 2]);
+    }
+
+    #[test]
+    fn series_covers_the_window() {
+        let mut history = History::new();
+        history.add(Metric::Temperature, minutes_ago(15), 20.0);
+        history.add(Metric::Temperature, minutes_ago(5), 21.0);
+
+        let raw = history.series(Metric::Temperature, Duration::from_secs(10 * 60), Resolution::Raw);
+        let means: Vec<f32> = raw.iter().map(|a| a.mean).collect();
+        assert_eq!(means, [21.0]);
+        assert!(history.series(Metric::Humidity, Duration::from_secs(60), Resolution::Raw).is_empty());
+    }
+
+    #[test]
+    fn series_includes_the_bucket_the_window_starts_in() {
+        let now = Utc::now().timestamp();
+        let aligned = now - now.rem_euclid(5 * 60);
+
+        let mut history = History::new();
+        for buckets_ago in [4, 3, 2, 1, 0] {
+            let at = DateTime::from_timestamp(aligned - buckets_ago * 5 * 60, 0).unwrap();
+            history.add(Metric::Co2, at, buckets_ago as f32);
+        }
+
+        // The window starts halfway through the bucket from 15 minutes ago,
+        // far enough from the edges that the clock ticking over doesn't matter
+        let since = aligned - 15 * 60 + 150;
+        let window = Duration::from_secs((now - since) as u64);
+
+        let five = history.series(Metric::Co2, window, Resolution::FiveMinutes);
+        let means: Vec<f32> = five.iter().map(|a| a.mean).collect();
+        assert_eq!(means, [3.0, 2.0, 1.0, 0.0]);
+    }
+
+    // A reading a minute, the last one now
+    fn co2_changing_by(per_minute: f32, readings: i64) -> History {
+        let now = Utc::now();
+        let mut history = History::new();
+        for i in 0..readings {
+            let at = now - chrono::Duration::minutes(readings - 1 - i);
+            history.add(Metric::Co2, at, 600.0 + per_minute * i as f32);
+        }
+        history
+    }
+
+    fn co2_trend(history: &History) -> Option<Trend> {
+        history.trend(Metric::Co2, Duration::from_secs(15 * 60))
+    }
+
+    #[test]
+    fn rising_trend() {
+        let trend = co2_trend(&co2_changing_by(10.0, 10)).expect("enough readings");
+        assert!((trend.per_minute - 10.0).abs() < 0.01);
+        assert_eq!(trend.latest, 690.0);
+        assert_eq!(trend.direction(), Direction::Rising);
+
+        let minutes = trend.minutes_until(1000.0).expect("heading there");
+        assert!((minutes - 31.0).abs() < 0.1);
+        assert_eq!(trend.minutes_until(500.0), None);
+    }
+
+    #[test]
+    fn falling_trend() {
+        let trend = co2_trend(&co2_changing_by(-20.0, 10)).expect("enough readings");
+        assert_eq!(trend.direction(), Direction::Falling);
+
+        let minutes = trend.minutes_until(400.0).expect("heading there");
+        assert!((minutes - 1.0).abs() < 0.1);
+        assert_eq!(trend.minutes_until(1000.0), None);
+    }
+
+    #[test]
+    fn steady_trend() {
+        // Below the 5 ppm a minute that counts as noise for CO2
+        let trend = co2_trend(&co2_changing_by(2.0, 10)).expect("enough readings");
+        assert_eq!(trend.direction(), Direction::Steady);
+        assert_eq!(trend.minutes_until(1000.0), None);
+
+        // The same rate is a lot for temperature
+        let temperature = Trend {
+            metric: Metric::Temperature,
+            ..trend
+        };
+        assert_eq!(temperature.direction(), Direction::Rising);
+    }
+
+    #[test]
+    fn no_trend_from_too_little() {
+        assert!(co2_trend(&History::new()).is_none());
+        assert!(co2_trend(&co2_changing_by(10.0, TREND_MIN_SAMPLES as i64 - 1)).is_none());
+        assert!(co2_trend(&co2_changing_by(10.0, TREND_MIN_SAMPLES as i64)).is_some());
+
+        // Plenty of readings, but all within a minute
+        let now = Utc::now();
+        let mut history = History::new();
+        for i in 0..10 {
+            history.add(Metric::Co2, now - chrono::Duration::seconds(60 - i * 6), 600.0 + i as f32);
+        }
+        assert!(co2_trend(&history).is_none());
+    }
+}
@@ -0,0 +1,197 @@
+use chrono::{DateTime, Local};
+
+use crate::config::{Clock, DisplayConfig, TemperatureUnit};
+use crate::sensors::{Comfort, Metric};
+
+fn symbol(unit: TemperatureUnit) -> &'static str {
+    match unit {
+        TemperatureUnit::Celsius => "ºC",
+        TemperatureUnit::Fahrenheit => "ºF",
+        TemperatureUnit::Kelvin => "K",
+    }
+}
+
+// How measurements and time are shown. Everything is measured and stored in
+// ºC, conversion only happens here, right before drawing.
+#[derive(Debug, Clone)]
+pub struct Locale {
+    pub temperature: TemperatureUnit,
+    clock_format: String,
+    date_format: Option<String>,
+    decimal_separator: char,
+}
+
+impl Locale {
+    pub fn new(config: &DisplayConfig) -> Self {
+        let clock_format = match (config.clock, config.seconds) {
+            (Clock::TwentyFourHour, true) => "%H:%M:%S",
+            (Clock::TwentyFourHour, false) => "%H:%M",
+            (Clock::TwelveHour, true) => "%-I:%M:%S%p",
+            (Clock::TwelveHour, false) => "%-I:%M %p",
+        };
+
+        Locale {
+            temperature: config.temperature_unit,
+            clock_format: clock_format.to_string(),
+            date_format: config.date_format.clone(),
+            decimal_separator: config.decimal_separator,
+        }
+    }
+
+    fn is_temperature(metric: Metric) -> bool {
+        matches!(metric, Metric::Temperature | Metric::DewPoint | Metric::HeatIndex)
+    }
+
+    // Converts a temperature, or any other metric unchanged
+    pub fn convert(&self, metric: Metric, value: f32) -> f32 {
+        if !Self::is_temperature(metric) {
+            return value;
+        }
+
+        match self.temperature {
+            TemperatureUnit::Celsius => value,
+            TemperatureUnit::Fahrenheit => value * 9.0 / 5.0 + 32.0,
+            TemperatureUnit::Kelvin => value + 273.15,
+        }
+    }
+
+    // Like `convert`, for differences such as offsets and rates of change
+    pub fn convert_delta(&self, metric: Metric, delta: f32) -> f32 {
+        match self.temperature {
+            TemperatureUnit::Fahrenheit if Self::is_temperature(metric) => delta * 9.0 / 5.0,
+            _ => delta,
+        }
+    }
+
+    pub fn unit(&self, metric: Metric) -> &'static str {
+        if Self::is_temperature(metric) {
+            symbol(self.temperature)
+        } else {
+            metric.unit()
+        }
+    }
+
+    pub fn number(&self, value: f32, precision: usize) -> String {
+        let formatted = format!("{:.*}", precision, value);
+        if self.decimal_separator == '.' {
+            formatted
+        } else {
+            formatted.replace('.', &self.decimal_separator.to_string())
+        }
+    }
+
+    // Converted value without unit, e.g. "71.6"
+    pub fn value(&self, metric: Metric, value: f32) -> String {
+        self.number(self.convert(metric, value), metric.precision())
+    }
+
+    // Converted value with its unit, e.g. "71.6 ºF" or "comfortable"
+    pub fn value_with_unit(&self, metric: Metric, value: f32) -> String {
+        let unit = self.unit(metric);
+        match metric {
+            Metric::Comfort => Comfort::from_value(value).label().to_string(),
+            _ if unit.is_empty() => self.value(metric, value),
+            _ => format!("{} {}", self.value(metric, value), unit),
+        }
+    }
+
+    pub fn time(&self, at: &DateTime<Local>) -> String {
+        at.format(&self.clock_format).to_string()
+    }
+
+    pub fn date(&self, at: &DateTime<Local>) -> Option<String> {
+        self.date_format
+            .as_ref()
+            .map(|format| at.format(format).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn locale(temperature_unit: TemperatureUnit) -> Locale {
+        Locale::new(&DisplayConfig {
+            temperature_unit,
+            ..DisplayConfig::default()
+        })
+    }
+
+    #[test]
+    fn convert() {
+        let celsius = locale(TemperatureUnit::Celsius);
+        let fahrenheit = locale(TemperatureUnit::Fahrenheit);
+        let kelvin = locale(TemperatureUnit::Kelvin);
+
+        assert_eq!(celsius.convert(Metric::Temperature, 20.0), 20.0);
+        assert_eq!(fahrenheit.convert(Metric::Temperature, 20.0), 68.0);
+        assert_eq!(fahrenheit.convert(Metric::DewPoint, -40.0), -40.0);
+        assert_eq!(kelvin.convert(Metric::HeatIndex, 20.0), 293.15);
+
+        // Only temperatures
+        assert_eq!(fahrenheit.convert(Metric::Humidity, 50.0), 50.0);
+        assert_eq!(kelvin.convert(Metric::Co2, 812.0), 812.0);
+        assert_eq!(fahrenheit.unit(Metric::Humidity), "%RH");
+        assert_eq!(kelvin.unit(Metric::Temperature), "K");
+    }
+
+    #[test]
+    fn convert_delta() {
+        assert_eq!(locale(TemperatureUnit::Fahrenheit).convert_delta(Metric::Temperature, 10.0), 18.0);
+        assert_eq!(locale(TemperatureUnit::Kelvin).convert_delta(Metric::Temperature, 10.0), 10.0);
+        assert_eq!(locale(TemperatureUnit::Celsius).convert_delta(Metric::Temperature, 10.0), 10.0);
+        assert_eq!(locale(TemperatureUnit::Fahrenheit).convert_delta(Metric::Co2, 10.0), 10.0);
+    }
+
+    #[test]
+    fn number() {
+        let comma = Locale::new(&DisplayConfig {
+            decimal_separator: ',',
+            ..DisplayConfig::default()
+        });
+        assert_eq!(comma.number(21.46, 1), "21,5");
+        assert_eq!(comma.number(812.0, 0), "812");
+        assert_eq!(locale(TemperatureUnit::Celsius).number(21.46, 1), "21.5");
+    }
+
+    #[test]
+    fn value_with_unit() {
+        let fahrenheit = locale(TemperatureUnit::Fahrenheit);
+        assert_eq!(fahrenheit.value_with_unit(Metric::Temperature, 21.5), "70.7 ºF");
+        assert_eq!(fahrenheit.value_with_unit(Metric::Co2, 812.4), "812 ppm");
+        assert_eq!(fahrenheit.value_with_unit(Metric::Humidex, 31.2), "31");
+        assert_eq!(fahrenheit.value_with_unit(Metric::Comfort, Comfort::Humid.value()), "humid");
+        assert_eq!(fahrenheit.value(Metric::Temperature, 21.5), "70.7");
+    }
+
+    #[test]
+    fn time() {
+        let at = Local.with_ymd_and_hms(2022, 5, 1, 14, 5, 9).unwrap();
+        let time = |clock, seconds| {
+            Locale::new(&DisplayConfig {
+                clock,
+                seconds,
+                ..DisplayConfig::default()
+            })
+            .time(&at)
+        };
+
+        assert_eq!(time(Clock::TwentyFourHour, true), "14:05:09");
+        assert_eq!(time(Clock::TwentyFourHour, false), "14:05");
+        assert_eq!(time(Clock::TwelveHour, true), "2:05:09PM");
+        assert_eq!(time(Clock::TwelveHour, false), "2:05 PM");
+    }
+
+    #[test]
+    fn date() {
+        let at = Local.with_ymd_and_hms(2022, 5, 1, 14, 5, 9).unwrap();
+        assert_eq!(locale(TemperatureUnit::Celsius).date(&at), None);
+
+        let locale = Locale::new(&DisplayConfig {
+            date_format: Some("%a %d %b".to_string()),
+            ..DisplayConfig::default()
+        });
+        assert_eq!(locale.date(&at).as_deref(), Some("Sun 01 May"));
+    }
+}
@@ -0,0 +1,306 @@
+use chrono::Local;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config::LogConfig;
+
+// Overrides `[log]` from the config, e.g. LEDDY_LOG=info,mqtt=debug,rumqttc=warn
+const ENV: &str = "LEDDY_LOG";
+
+// Writes log lines to stderr, and to a file if one is configured. Under
+// systemd stderr lines get a `<priority>` prefix instead of the level name and
+// timestamp, so journald files them at the right level and time.
+struct Logger {
+    level: LevelFilter,
+    modules: Vec<(String, LevelFilter)>, // longest first, so the most specific match wins
+    journald: bool,
+    file: Option<Mutex<LogFile>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let now = Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+        let line = format!("{:<5} {}: {}", record.level(), short_target(record.target()), record.args());
+
+        let stderr = std::io::stderr();
+        let mut out = stderr.lock();
+        // Nowhere left to report a failure to log
+        let _ = if self.journald {
+            writeln!(out, "<{}>{}", priority(record.level()), line)
+        } else {
+            writeln!(out, "{} {}", now, line)
+        };
+
+        if let Some(file) = &self.file {
+            let mut file = file.lock().expect("lock log file");
+            if let Err(e) = file.write(&format!("{} {}\n", now, line)) {
+                let _ = writeln!(out, "Could not write to log file {}: {}", file.path.display(), e);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl Logger {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| matches_module(target, module))
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+}
+
+// `mqtt` and `leddy::mqtt` both match the target `leddy::mqtt` and its submodules
+fn matches_module(target: &str, module: &str) -> bool {
+    let target = short_target(target);
+    let module = short_target(module);
+    target == module || target.strip_prefix(module).map_or(false, |rest| rest.starts_with("::"))
+}
+
+fn short_target(target: &str) -> &str {
+    target.strip_prefix("leddy::").unwrap_or(target)
+}
+
+// sd-daemon(3) priorities
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// Appends to a file, moving it to `<file>.1`, `<file>.2`, ... once it is full
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_size: u64, keep: usize) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            max_size,
+            keep,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        if self.max_size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+
+        // The oldest one falls off the end
+        for n in (1..self.keep).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, rotated(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+// Parses `level` or `level,module=level,...`
+fn parse_filters(spec: &str) -> Result<(Option<LevelFilter>, Vec<(String, LevelFilter)>), String> {
+    let mut level = None;
+    let mut modules = Vec::new();
+
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match part.split_once('=') {
+            Some((module, l)) => modules.push((module.trim().to_string(), parse_level(l.trim())?)),
+            None => level = Some(parse_level(part)?),
+        }
+    }
+
+    Ok((level, modules))
+}
+
+pub fn parse_level(s: &str) -> Result<LevelFilter, String> {
+    s.parse()
+        .map_err(|_| format!("log level should be off, error, warn, info, debug or trace, not `{}`", s))
+}
+
+pub fn init(config: &LogConfig) {
+    let mut level = config.level;
+    let mut modules: Vec<(String, LevelFilter)> =
+        config.modules.iter().map(|(module, l)| (module.clone(), *l)).collect();
+
+    let mut env_error = None;
+    if let Ok(spec) = std::env::var(ENV) {
+        match parse_filters(&spec) {
+            Ok((env_level, env_modules)) => {
+                level = env_level.unwrap_or(level);
+                // Later entries win over the config ones for the same module
+                modules.retain(|(m, _)| !env_modules.iter().any(|(e, _)| short_target(e) == short_target(m)));
+                modules.extend(env_modules);
+            }
+            Err(e) => env_error = Some(e),
+        }
+    }
+    modules.sort_by_key(|(module, _)| std::cmp::Reverse(short_target(module).len()));
+
+    let mut file_error = None;
+    let file = config.file.as_ref().and_then(|path| {
+        match LogFile::open(Path::new(path), config.max_size, config.keep) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => {
+                file_error = Some(format!("Could not open log file {}: {}", path, e));
+                None
+            }
+        }
+    });
+
+    let max_level = modules.iter().map(|(_, l)| *l).chain([level]).max().unwrap_or(level);
+    let logger = Logger {
+        level,
+        modules,
+        // systemd sets this when stderr goes to the journal
+        journald: std::env::var_os("JOURNAL_STREAM").is_some(),
+        file,
+    };
+
+    log::set_boxed_logger(Box::new(logger)).expect("logger is only set once");
+    log::set_max_level(max_level);
+
+    // Reported once there is a logger to report them
+    if let Some(e) = env_error {
+        log::warn!("Ignoring {}: {}", ENV, e);
+    }
+    if let Some(e) = file_error {
+        log::error!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logger(level: LevelFilter, modules: &[(&str, LevelFilter)]) -> Logger {
+        Logger {
+            level,
+            modules: modules.iter().map(|(m, l)| (m.to_string(), *l)).collect(),
+            journald: false,
+            file: None,
+        }
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(parse_filters("debug"), Ok((Some(LevelFilter::Debug), vec![])));
+        assert_eq!(parse_filters(""), Ok((None, vec![])));
+        assert_eq!(
+            parse_filters(" info, mqtt = debug,,leddy::sensors=TRACE "),
+            Ok((
+                Some(LevelFilter::Info),
+                vec![
+                    ("mqtt".to_string(), LevelFilter::Debug),
+                    ("leddy::sensors".to_string(), LevelFilter::Trace),
+                ]
+            ))
+        );
+        // Only the modules, the level stays as configured
+        assert_eq!(
+            parse_filters("rumqttc=off"),
+            Ok((None, vec![("rumqttc".to_string(), LevelFilter::Off)]))
+        );
+    }
+
+    #[test]
+    fn bad_filters() {
+        let e = parse_filters("info,mqtt=loud").unwrap_err();
+        assert_eq!(e, "log level should be off, error, warn, info, debug or trace, not `loud`");
+        assert!(parse_filters("verbose").is_err());
+        assert!(parse_filters("mqtt=").is_err());
+    }
+
+    #[test]
+    fn modules() {
+        assert!(matches_module("leddy::mqtt", "mqtt"));
+        assert!(matches_module("leddy::mqtt", "leddy::mqtt"));
+        assert!(matches_module("leddy::sensors::scd30", "sensors"));
+        assert!(matches_module("rumqttc::state", "rumqttc"));
+
+        assert!(!matches_module("leddy::mqtt_bridge", "mqtt"));
+        assert!(!matches_module("leddy::sensors", "sensors::scd30"));
+        assert!(!matches_module("rumqttc", "mqtt"));
+
+        assert_eq!(short_target("leddy::api"), "api");
+        assert_eq!(short_target("tiny_http"), "tiny_http");
+    }
+
+    #[test]
+    fn most_specific_module_wins() {
+        // Sorted longest first by `init`
+        let logger = logger(
+            LevelFilter::Info,
+            &[("sensors::scd30", LevelFilter::Trace), ("sensors", LevelFilter::Warn)],
+        );
+        assert_eq!(logger.level_for("leddy::sensors::scd30"), LevelFilter::Trace);
+        assert_eq!(logger.level_for("leddy::sensors::bme280"), LevelFilter::Warn);
+        assert_eq!(logger.level_for("leddy::mqtt"), LevelFilter::Info);
+
+        let debug = Metadata::builder().level(Level::Debug).target("leddy::sensors::scd30").build();
+        let info = Metadata::builder().level(Level::Info).target("leddy::sensors").build();
+        assert!(logger.enabled(&debug));
+        assert!(!logger.enabled(&info));
+    }
+
+    #[test]
+    fn log_files_are_rotated() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let path = dir.path().join("leddy.log");
+        let rotated = |n: usize| dir.path().join(format!("leddy.log.{}", n));
+
+        let mut file = LogFile::open(&path, 10, 2).expect("open");
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n", "six\n"] {
+            file.write(line).expect("write");
+        }
+
+        // "one" and "two" fell off the end
+        let read = |path: PathBuf| fs::read_to_string(path).expect("read");
+        assert_eq!(read(path.clone()), "six\n");
+        assert_eq!(read(rotated(1)), "four\nfive\n");
+        assert_eq!(read(rotated(2)), "three\n");
+        assert!(!rotated(3).exists());
+    }
+}
@@ -1,103 +1,353 @@
-use embedded_graphics::{
-    pixelcolor::Rgb888,
-    prelude::*,
-    primitives::{PrimitiveStyleBuilder, Rectangle},
-};
-use rpi_led_matrix::{LedCanvas, LedMatrix, LedMatrixOptions, LedRuntimeOptions};
+use embedded_graphics::{pixelcolor::Rgb888, prelude::*};
+use log::{debug, error, warn};
+use rpi_led_matrix::{LedMatrix, LedMatrixOptions, LedRuntimeOptions};
 use signal_hook::{consts::TERM_SIGNALS, flag};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 
 mod screens;
-use screens::Screen;
+use screens::{Canvas, Screen};
 use screens::BackgroundScreen;
 use screens::WaveScreen;
 use screens::MazeScreen;
+use screens::DiagnosticsScreen;
+use screens::GraphScreen;
 
+mod alerts;
+mod api;
+mod cache;
+mod commands;
+mod config;
+mod control;
+mod downloader;
+mod error;
+mod export;
+mod exporters;
+mod history;
+mod locale;
+mod logging;
 mod mailbox;
+mod messages;
+mod metrics;
+mod mqtt;
+mod preview;
 mod rotary;
+mod sensors;
+mod shutdown;
+mod state;
+mod storage;
+mod supervisor;
+mod systemd;
 use rotary::InputEvent;
+use mailbox::Mailbox;
+use history::History;
+use storage::Storage;
+use alerts::Alerts;
+use api::DisplayState;
+use commands::Command;
+use messages::Messages;
+use preview::Frame;
+use locale::Locale;
+use metrics::{FrameTimer, Metrics};
+use shutdown::{Goodbye, Workers};
+use state::{State, StateFile};
+use supervisor::Supervisor;
+use systemd::Watchdog;
 
-fn main() {
+fn main() -> ExitCode {
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    logging::init(&config.log);
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command: Option<fn(&config::Config, &[String]) -> Result<(), String>> =
+        match args.first().map(String::as_str) {
+            Some("export") => Some(export::run),
+            Some("ctl") => Some(control::run),
+            _ => None,
+        };
+    if let Some(command) = command {
+        if let Err(e) = command(&config, &args[1..]) {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let locale = Locale::new(&config.display);
     let term = setup_signal_trapping();
-    let irx = start_input_thread(&term);
+    let workers = Workers::new();
+    let irx = start_input_thread(&term, &workers);
+
+    let readings = Mailbox::new();
+    let history = History::shared();
+    let counters = Metrics::shared();
+    if config.metrics.enabled {
+        let handle = metrics::spawn(
+            &config.metrics,
+            Arc::clone(&counters),
+            Arc::clone(&history),
+            Arc::clone(&term),
+        );
+        if let Some(handle) = handle {
+            workers.add("metrics", handle);
+        }
+    }
+    let storage = if config.storage.enabled {
+        match Storage::open(&config.storage) {
+            Ok(storage) => {
+                let handle = storage::reload(&config.storage, Arc::clone(&history), Arc::clone(&term));
+                workers.add("history", handle);
+                Some(storage)
+            }
+            Err(e) => {
+                error!("Storage disabled, cannot open {}: {}", config.storage.dir, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let sensor_status = Mailbox::new();
+    let (sensor_tx, sensor_rx) = channel();
+    let (command_tx, command_rx) = channel();
+    let mut subscribers = Vec::new();
+    if config.mqtt.enabled {
+        let (tx, rx) = channel();
+        workers.add("mqtt", mqtt::spawn(&config.mqtt, rx, command_tx.clone(), Arc::clone(&term)));
+        subscribers.push(tx);
+    }
+    for (tx, handle) in exporters::spawn(&config, &term) {
+        workers.add("exporter", handle);
+        subscribers.push(tx);
+    }
+    let handle = sensors::spawn(
+        &config,
+        sensors::Sensors {
+            readings: readings.clone(),
+            history: Arc::clone(&history),
+            storage,
+            scd30_status: sensor_status.clone(),
+            scd30_commands: sensor_rx,
+            metrics: Arc::clone(&counters),
+            subscribers,
+        },
+        Arc::clone(&term),
+    );
+    workers.add("sensors", handle);
+
+    let display_state = DisplayState::shared();
+    let frame = Frame::shared(screens::WIDTH, screens::HEIGHT);
+    if config.api.enabled {
+        let handle = api::spawn(
+            &config.api,
+            command_tx.clone(),
+            Arc::clone(&display_state),
+            Arc::clone(&history),
+            Arc::clone(&frame),
+            workers.clone(),
+            Arc::clone(&term),
+        );
+        if let Some(handle) = handle {
+            workers.add("api", handle);
+        }
+    }
 
-    let mut background = BackgroundScreen::new(Arc::clone(&term));
+    let (download_tx, download_rx) = channel();
+    let handle = downloader::spawn(&config, download_tx, Arc::clone(&counters), Arc::clone(&term));
+    workers.add("downloader", handle);
 
-    let matrix = setup_matrix();
+    let mut background = BackgroundScreen::new(
+        &config.background,
+        &locale,
+        readings,
+        Arc::clone(&history),
+        download_rx,
+    );
 
-    let mut canvas = matrix.offscreen_canvas();
+    let mut canvas = Canvas::new(setup_matrix(), Arc::clone(&frame));
     canvas.clear();
-    canvas = matrix.swap(canvas);
+    canvas.swap();
 
     let mut wave = WaveScreen::new(&canvas);
     let mut maze = MazeScreen::new(&canvas);
+    let mut graph = GraphScreen::new(&canvas, &locale, Arc::clone(&history));
+    let mut diagnostics = DiagnosticsScreen::new(&locale, sensor_status, sensor_tx);
 
-    let border_style = PrimitiveStyleBuilder::new()
-        .stroke_color(Rgb888::WHITE)
-        .stroke_width(1)
-        .build();
-
-    let selection_mode_border =
-        Rectangle::new(Point::new(0, 0), Size::new(64, 32)).into_styled(border_style);
+    let selection_mode_border = screens::border(Rgb888::WHITE, 1);
+    let mut alerts = Alerts::new(&config.alerts, Arc::clone(&history));
+    let goodbye = Goodbye::new(&config.shutdown);
+    let mut messages = Messages::new();
+    let mut display_on = true;
 
-    let mut screen_idx = 0usize;
+    let mut frame_timer = FrameTimer::new(Arc::clone(&counters));
+    let mut watchdog = Watchdog::new();
+    let mut ready = false;
 
     let mut selection_mode = false;
-    let screens = [
+    let mut screens = [
         &mut background as &mut dyn Screen,
         &mut wave as &mut dyn Screen,
         &mut maze as &mut dyn Screen,
+        &mut graph as &mut dyn Screen,
+        &mut diagnostics as &mut dyn Screen,
     ];
+    let mut supervisor = Supervisor::new(screens.len());
+
+    if config.control.enabled {
+        let handle = control::spawn(
+            &config.control,
+            command_tx.clone(),
+            Arc::clone(&display_state),
+            Arc::clone(&history),
+            frame,
+            screens.iter().map(|s| s.name()).collect(),
+            workers.clone(),
+            Arc::clone(&term),
+        );
+        if let Some(handle) = handle {
+            workers.add("control", handle);
+        }
+    }
+
+    let (mut state_file, saved) = StateFile::open(&config.storage);
+    let mut screen_idx = saved.restore(&mut screens).unwrap_or(0);
+    if let Some(brightness) = saved.brightness {
+        canvas.set_brightness(brightness);
+    }
 
     while !term.load(Ordering::Relaxed) {
+        let frame_start = Instant::now();
         canvas.clear();
 
-        if let Ok(evt) = irx.try_recv() {
-            if selection_mode {
-                let ds = match evt {
-                    InputEvent::Left => -1,
-                    InputEvent::Right => 1,
-                    _ => {
-                        selection_mode = false;
-                        0
-                    }
-                };
-
-                let ilen = screens.len() as isize;
-                screen_idx = ((screen_idx as isize + ds) % ilen + ilen) as usize % screens.len();
-            } else {
-                match evt {
-                    InputEvent::Left => screens[screen_idx].left(),
-                    InputEvent::Right => screens[screen_idx].right(),
-                    InputEvent::Click => screens[screen_idx].click(),
-                    InputEvent::LongPress => selection_mode = true,
-                };
+        alerts.update();
+
+        // The encoder goes first, remote commands wait until it is idle
+        let command = match irx.try_recv() {
+            Ok(evt) => Some(Command::Input(evt)),
+            Err(_) => command_rx.try_recv().ok(),
+        };
+        if let Some(command) = &command {
+            debug!("Command {:?}", command);
+        }
+
+        match command {
+            Some(Command::Input(_)) if !display_on => {
+                // Any input wakes the display up, without doing anything else
+                display_on = true;
+            }
+            Some(Command::Input(evt)) => {
+                if messages.active() && evt == InputEvent::Click {
+                    messages.dismiss();
+                } else if alerts.active() && evt == InputEvent::Click {
+                    alerts.acknowledge();
+                } else if selection_mode {
+                    let ds = match evt {
+                        InputEvent::Left => -1,
+                        InputEvent::Right => 1,
+                        _ => {
+                            selection_mode = false;
+                            0
+                        }
+                    };
+
+                    let ilen = screens.len() as isize;
+                    screen_idx = ((screen_idx as isize + ds) % ilen + ilen) as usize % screens.len();
+                    screen_idx = supervisor.enabled(screen_idx, ds >= 0);
+                } else {
+                    match evt {
+                        InputEvent::Left => screens[screen_idx].left(),
+                        InputEvent::Right => screens[screen_idx].right(),
+                        InputEvent::Click => screens[screen_idx].click(),
+                        InputEvent::LongPress => selection_mode = true,
+                    };
+                }
             }
+            Some(Command::Screen(name)) => match screens.iter().position(|s| s.name() == name) {
+                Some(idx) => {
+                    supervisor.enable(idx, &mut *screens[idx]);
+                    screen_idx = idx;
+                    selection_mode = false;
+                }
+                None => warn!("No screen named `{}`", name),
+            },
+            Some(Command::Brightness(percent)) => canvas.set_brightness(percent),
+            Some(Command::Message(text)) => messages.show(text),
+            Some(Command::Display(on)) => display_on = on,
+            None => {}
         }
 
-        screens[screen_idx].draw(&mut canvas);
+        if display_on {
+            supervisor.draw(screen_idx, &mut *screens[screen_idx], &mut canvas);
+            // Moves on if that was the last straw for the screen
+            screen_idx = supervisor.enabled(screen_idx, true);
 
-        if selection_mode {
-            selection_mode_border
-                .draw(&mut canvas)
-                .expect("draw border");
+            if alerts.active() {
+                if let Err(e) = alerts.draw(&mut canvas) {
+                    error!("Could not draw the alert: {}", e);
+                }
+            }
+
+            if messages.active() {
+                if let Err(e) = messages.draw(&mut canvas) {
+                    error!("Could not draw the message: {}", e);
+                }
+            }
+
+            if selection_mode {
+                if let Err(e) = selection_mode_border.draw(&mut canvas) {
+                    error!("Could not draw the selection border: {}", e);
+                }
+            }
         }
 
-        canvas = matrix.swap(canvas);
+        canvas.swap();
+        if !ready {
+            // The first frame is on the panel, startup is over
+            systemd::notify("READY=1");
+            ready = true;
+        }
+        watchdog.ping();
+        counters.set_active_screen(screen_idx);
+        *display_state.lock().expect("lock display state") = DisplayState {
+            screen: screens[screen_idx].name(),
+            brightness: canvas.brightness(),
+            on: display_on,
+        };
+        state_file.update(|| State::capture(&screens, screen_idx, canvas.brightness()));
+        frame_timer.frame(frame_start.elapsed());
         thread::sleep(Duration::from_millis(1));
     }
 
-    // Cleanup
+    // Cleanup, the workers saw the term flag too and are stopping meanwhile
+    state_file.flush(State::capture(&screens, screen_idx, canvas.brightness()));
+    systemd::notify("STOPPING=1");
+    if display_on {
+        goodbye.show(&mut canvas, &mut *screens[screen_idx]);
+    }
     canvas.clear();
-    canvas = matrix.swap(canvas);
+    canvas.swap();
+
+    let failed = workers.join(Duration::from_secs(config.shutdown.timeout));
+    if failed > 0 {
+        error!("{} workers failed", failed);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
 }
 
-fn setup_matrix() -> LedMatrix {
+// No matrix is not fatal, the display can still be watched in the web preview
+fn setup_matrix() -> Option<LedMatrix> {
     let mut options = LedMatrixOptions::new();
     options.set_hardware_mapping("adafruit-hat-pwm");
     options.set_brightness(100);
@@ -107,7 +357,13 @@ fn setup_matrix() -> LedMatrix {
     let mut rt_options = LedRuntimeOptions::new();
     rt_options.set_gpio_slowdown(0);
 
-    LedMatrix::new(Some(options), Some(rt_options)).expect("init matrix")
+    match LedMatrix::new(Some(options), Some(rt_options)) {
+        Ok(matrix) => Some(matrix),
+        Err(e) => {
+            warn!("No LED matrix, running without one: {}", e);
+            None
+        }
+    }
 }
 
 fn setup_signal_trapping() -> Arc<AtomicBool> {
@@ -119,14 +375,27 @@ fn setup_signal_trapping() -> Arc<AtomicBool> {
     term
 }
 
-fn start_input_thread(term: &Arc<AtomicBool>) -> Receiver<InputEvent> {
+fn start_input_thread(term: &Arc<AtomicBool>, workers: &Workers) -> Receiver<InputEvent> {
     let (itx, irx) = channel::<InputEvent>();
     let term_ = Arc::clone(term);
 
-    thread::spawn(move || {
-        let mut listener = rotary::RotaryEncoder::new(itx);
-        listener.poll_loop(term_);
-    });
+    // Like the matrix, the display still works without it, only remotely
+    let mut listener = match rotary::RotaryEncoder::new(itx) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("No rotary encoder, running without one: {}", e);
+            return irx;
+        }
+    };
+
+    workers.add(
+        "input",
+        thread::spawn(move || {
+            if let Err(e) = listener.poll_loop(term_) {
+                error!("Rotary encoder stopped: {}", e);
+            }
+        }),
+    );
 
     irx
 }
@@ -0,0 +1,81 @@
+use embedded_graphics::prelude::*;
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_6X10, MonoTextStyle},
+    pixelcolor::Rgb888,
+    primitives::{PrimitiveStyleBuilder, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::screens::Canvas;
+
+// How long a message stays up unless it is clicked away
+const SHOW_FOR: Duration = Duration::from_secs(15);
+
+// Messages too long for the panel scroll by at this many pixels per second
+const SCROLL_SPEED: f32 = 20.0;
+
+const CHAR_WIDTH: i32 = 6;
+const WIDTH: i32 = 64;
+
+// A text pushed from outside, e.g. "doorbell", shown on top of the current screen
+#[derive(Default)]
+pub struct Messages {
+    current: Option<(String, Instant)>,
+}
+
+impl Messages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces whatever message was showing
+    pub fn show(&mut self, text: String) {
+        self.current = Some((text, Instant::now()));
+    }
+
+    pub fn active(&self) -> bool {
+        matches!(&self.current, Some((_, shown)) if shown.elapsed() < SHOW_FOR)
+    }
+
+    pub fn dismiss(&mut self) {
+        self.current = None;
+    }
+
+    pub fn draw(&self, canvas: &mut Canvas) -> Result<()> {
+        let (text, shown) = match &self.current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+
+        let box_style = PrimitiveStyleBuilder::new()
+            .fill_color(Rgb888::BLACK)
+            .stroke_color(Rgb888::CYAN)
+            .stroke_width(1)
+            .build();
+        Rectangle::new(Point::new(0, 8), Size::new(WIDTH as u32, 16))
+            .into_styled(box_style)
+            .draw(canvas)?;
+
+        let style = MonoTextStyle::new(&FONT_6X10, Rgb888::WHITE);
+        let width = text.chars().count() as i32 * CHAR_WIDTH;
+        let (at, alignment) = if width <= WIDTH - 4 {
+            (Point::new(WIDTH / 2, 16), Alignment::Center)
+        } else {
+            // Scroll in from the right, then start over
+            let offset = (shown.elapsed().as_secs_f32() * SCROLL_SPEED) as i32 % (width + WIDTH);
+            (Point::new(WIDTH - offset, 16), Alignment::Left)
+        };
+        let text_style = TextStyleBuilder::new()
+            .alignment(alignment)
+            .baseline(Baseline::Middle)
+            .build();
+
+        // Keep scrolling text inside the box
+        let inside = Rectangle::new(Point::new(1, 9), Size::new(WIDTH as u32 - 2, 14));
+        Text::with_text_style(text, at, style, text_style)
+            .draw(&mut canvas.clipped(&inside))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use chrono::Utc;
+use log::{error, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Response, Server};
+
+use crate::config::MetricsConfig;
+use crate::history::SharedHistory;
+use crate::sensors::Metric;
+
+pub type SharedMetrics = Arc<Metrics>;
+
+// Counters and gauges updated from all over, served in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    sensor_errors: Mutex<BTreeMap<&'static str, u64>>,
+    frames: AtomicU64,
+    frame_rate: AtomicU64, // f64 bits
+    frame_time: AtomicU64, // f64 bits, seconds
+    active_screen: AtomicUsize,
+    background_failures: AtomicU64,
+}
+
+impl Metrics {
+    pub fn shared() -> SharedMetrics {
+        Arc::new(Self::default())
+    }
+
+    pub fn sensor_error(&self, sensor: &'static str) {
+        *self
+            .sensor_errors
+            .lock()
+            .expect("lock metrics")
+            .entry(sensor)
+            .or_insert(0) += 1;
+    }
+
+    pub fn background_failure(&self) {
+        self.background_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_active_screen(&self, index: usize) {
+        self.active_screen.store(index, Ordering::Relaxed);
+    }
+
+    fn render(&self, history: &SharedHistory) -> String {
+        let mut out = String::new();
+
+        let latest: Vec<_> = {
+            let history = history.lock().expect("lock history");
+            Metric::ALL
+                .iter()
+                .filter_map(|m| history.latest(*m).map(|l| (*m, l)))
+                .collect()
+        };
+        let now = Utc::now();
+
+        header(&mut out, "leddy_reading", "gauge", "Latest value of every sensor reading");
+        for (metric, latest) in &latest {
+            writeln!(
+                out,
+                "leddy_reading{{metric=\"{}\",unit=\"{}\"}} {}",
+                metric.name(),
+                metric.unit(),
+                latest.mean
+            )
+            .ok();
+        }
+
+        header(&mut out, "leddy_reading_age_seconds", "gauge", "Time since the latest value of every sensor reading");
+        for (metric, latest) in &latest {
+            let age = (now - latest.start).num_milliseconds() as f64 / 1000.0;
+            writeln!(out, "leddy_reading_age_seconds{{metric=\"{}\"}} {}", metric.name(), age).ok();
+        }
+
+        header(&mut out, "leddy_sensor_errors_total", "counter", "Failed sensor polls and commands");
+        for (sensor, count) in self.sensor_errors.lock().expect("lock metrics").iter() {
+            writeln!(out, "leddy_sensor_errors_total{{sensor=\"{}\"}} {}", sensor, count).ok();
+        }
+
+        header(&mut out, "leddy_frames_total", "counter", "Frames drawn");
+        writeln!(out, "leddy_frames_total {}", self.frames.load(Ordering::Relaxed)).ok();
+
+        header(&mut out, "leddy_frame_rate", "gauge", "Frames per second, over the last second");
+        writeln!(out, "leddy_frame_rate {}", load_f64(&self.frame_rate)).ok();
+
+        header(&mut out, "leddy_frame_time_seconds", "gauge", "Mean time spent on a frame, over the last second");
+        writeln!(out, "leddy_frame_time_seconds {}", load_f64(&self.frame_time)).ok();
+
+        header(&mut out, "leddy_active_screen", "gauge", "Index of the screen being shown");
+        writeln!(out, "leddy_active_screen {}", self.active_screen.load(Ordering::Relaxed)).ok();
+
+        header(
+            &mut out,
+            "leddy_background_download_failures_total",
+            "counter",
+            "Background images that could not be downloaded",
+        );
+        writeln!(
+            out,
+            "leddy_background_download_failures_total {}",
+            self.background_failures.load(Ordering::Relaxed)
+        )
+        .ok();
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).ok();
+    writeln!(out, "# TYPE {} {}", name, kind).ok();
+}
+
+// Atomics have no f64, so gauges are stored as their bits
+fn load_f64(value: &AtomicU64) -> f64 {
+    f64::from_bits(value.load(Ordering::Relaxed))
+}
+
+// Averages frame rate and frame time over one second windows
+pub struct FrameTimer {
+    metrics: SharedMetrics,
+    window_start: Instant,
+    frames: u32,
+    busy: Duration,
+}
+
+impl FrameTimer {
+    pub fn new(metrics: SharedMetrics) -> Self {
+        FrameTimer {
+            metrics,
+            window_start: Instant::now(),
+            frames: 0,
+            busy: Duration::ZERO,
+        }
+    }
+
+    // Call once per frame with the time it took, not counting the sleep
+    pub fn frame(&mut self, busy: Duration) {
+        self.metrics.frames.fetch_add(1, Ordering::Relaxed);
+        self.frames += 1;
+        self.busy += busy;
+
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let rate = self.frames as f64 / elapsed.as_secs_f64();
+            let time = self.busy.as_secs_f64() / self.frames as f64;
+            self.metrics.frame_rate.store(rate.to_bits(), Ordering::Relaxed);
+            self.metrics.frame_time.store(time.to_bits(), Ordering::Relaxed);
+
+            self.window_start = Instant::now();
+            self.frames = 0;
+            self.busy = Duration::ZERO;
+        }
+    }
+}
+
+pub fn spawn(
+    config: &MetricsConfig,
+    metrics: SharedMetrics,
+    history: SharedHistory,
+    cancel: Arc<AtomicBool>,
+) -> Option<JoinHandle<()>> {
+    let server = match Server::http(&config.listen) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Metrics disabled, cannot listen on {}: {}", config.listen, e);
+            return None;
+        }
+    };
+
+    Some(thread::spawn(move || {
+        let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+            .expect("valid header");
+
+        while !cancel.load(Ordering::Relaxed) {
+            let request = match server.recv_timeout(Duration::from_millis(500)) {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("Metrics server error: {}", e);
+                    continue;
+                }
+            };
+
+            let response = if request.url() == "/metrics" {
+                Response::from_string(metrics.render(&history)).with_header(content_type.clone())
+            } else {
+                Response::from_string("Not found").with_status_code(404)
+            };
+
+            if let Err(e) = request.respond(response) {
+                warn!("Metrics response error: {}", e);
+            }
+        }
+    }))
+}
@@ -0,0 +1,381 @@
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS, RecvTimeoutError};
+use serde_json::json;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::commands::Command;
+use crate::config::{MqttConfig, Qos};
+use crate::sensors::{Comfort, Metric, Readings};
+
+// How long to wait for broker events before checking for new readings
+const POLL_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Pause between reconnection attempts while the broker is unreachable
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Capacity of the client's own request channel, our buffer sits in front of it
+const REQUEST_CAPACITY: usize = 64;
+
+struct Message {
+    topic: String,
+    payload: String,
+    retain: bool,
+}
+
+// Publishes every new reading to `<prefix>/<metric>`, with Home Assistant
+// discovery configs so the sensors show up there without any setup. Readings
+// are buffered while the broker is down and sent once it is back. Commands
+// published to `<prefix>/command/<name>` are passed on to the main loop.
+struct Publisher {
+    config: MqttConfig,
+    commands: Sender<Command>,
+    qos: QoS,
+    connected: bool,
+    announced: BTreeSet<Metric>,
+    buffer: VecDeque<Message>,
+}
+
+pub fn spawn(
+    config: &MqttConfig,
+    rx: Receiver<(DateTime<Utc>, Readings)>,
+    commands: Sender<Command>,
+    cancel: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    let mut publisher = Publisher::new(config, commands);
+    thread::spawn(move || publisher.run(rx, &cancel))
+}
+
+impl Publisher {
+    fn new(config: &MqttConfig, commands: Sender<Command>) -> Self {
+        let qos = match config.qos {
+            Qos::AtMostOnce => QoS::AtMostOnce,
+            Qos::AtLeastOnce => QoS::AtLeastOnce,
+            Qos::ExactlyOnce => QoS::ExactlyOnce,
+        };
+
+        Publisher {
+            config: config.clone(),
+            commands,
+            qos,
+            connected: false,
+            announced: BTreeSet::new(),
+            buffer: VecDeque::new(),
+        }
+    }
+
+    fn run(&mut self, rx: Receiver<(DateTime<Utc>, Readings)>, cancel: &AtomicBool) {
+        let mut options = MqttOptions::new(&self.config.client_id, &self.config.host, self.config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(self.status_topic(), "offline", QoS::AtLeastOnce, true));
+        if let Some(username) = &self.config.username {
+            options.set_credentials(username, self.config.password.clone().unwrap_or_default());
+        }
+
+        let (client, mut connection) = Client::new(options, REQUEST_CAPACITY);
+
+        while !cancel.load(Ordering::Relaxed) {
+            for (_, readings) in rx.try_iter() {
+                self.queue(&readings);
+            }
+
+            if self.connected {
+                self.flush(&client);
+            }
+
+            match connection.recv_timeout(POLL_TIMEOUT) {
+                Ok(Ok(Event::Incoming(Packet::ConnAck(_)))) => {
+                    info!("Connected to MQTT broker {}:{}", self.config.host, self.config.port);
+                    self.connected = true;
+                    if let Err(e) = client.try_subscribe(self.command_topic("+"), self.qos) {
+                        error!("Could not subscribe to MQTT commands: {}", e);
+                    }
+                    // The broker may have lost the retained configs, announce again
+                    self.announced.clear();
+                    self.buffer.push_front(Message {
+                        topic: self.status_topic(),
+                        payload: "online".to_string(),
+                        retain: true,
+                    });
+                }
+                Ok(Ok(Event::Incoming(Packet::Publish(publish)))) => {
+                    // A retained command would be replayed on every reconnect
+                    if !publish.retain {
+                        self.command(&publish.topic, &publish.payload);
+                    }
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    if self.connected {
+                        warn!("MQTT connection lost: {}", e);
+                    }
+                    self.connected = false;
+                    thread::sleep(RECONNECT_DELAY);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        if self.connected {
+            client
+                .try_publish(self.status_topic(), QoS::AtLeastOnce, true, "offline")
+                .ok();
+            client.try_disconnect().ok();
+            // Let the event loop send what we just queued
+            while let Ok(Ok(_)) = connection.recv_timeout(POLL_TIMEOUT) {}
+        }
+    }
+
+    fn queue(&mut self, readings: &Readings) {
+        for (metric, value) in readings.iter() {
+            if self.config.discovery && self.announced.insert(metric) {
+                let config = self.discovery_config(metric);
+                self.push(Message {
+                    topic: format!(
+                        "{}/sensor/{}/{}/config",
+                        self.config.discovery_prefix,
+                        self.config.client_id,
+                        metric.name()
+                    ),
+                    payload: config.to_string(),
+                    retain: true,
+                });
+            }
+
+            let payload = match metric {
+                Metric::Comfort => Comfort::from_value(value).label().to_string(),
+                _ => format!("{:.*}", metric.precision(), value),
+            };
+            self.push(Message {
+                topic: self.state_topic(metric),
+                payload,
+                retain: self.config.retain,
+            });
+        }
+    }
+
+    // Drops the oldest messages once the buffer is full
+    fn push(&mut self, message: Message) {
+        if self.buffer.len() >= self.config.buffer {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(message);
+    }
+
+    // Hands buffered messages to the client until its request channel is full
+    fn flush(&mut self, client: &Client) {
+        while let Some(message) = self.buffer.front() {
+            let sent = client.try_publish(
+                message.topic.as_str(),
+                self.qos,
+                message.retain,
+                message.payload.as_bytes(),
+            );
+            if sent.is_err() {
+                break;
+            }
+            self.buffer.pop_front();
+        }
+    }
+
+    fn command(&self, topic: &str, payload: &[u8]) {
+        let name = match topic.strip_prefix(&self.command_topic("")) {
+            Some(name) => name,
+            None => return,
+        };
+
+        match Command::parse(name, &String::from_utf8_lossy(payload)) {
+            Ok(command) => {
+                self.commands.send(command).ok();
+            }
+            Err(e) => warn!("Ignoring MQTT command on {}: {}", topic, e),
+        }
+    }
+
+    fn command_topic(&self, name: &str) -> String {
+        format!("{}/command/{}", self.config.prefix, name)
+    }
+
+    fn status_topic(&self) -> String {
+        format!("{}/status", self.config.prefix)
+    }
+
+    fn state_topic(&self, metric: Metric) -> String {
+        format!("{}/{}", self.config.prefix, metric.name())
+    }
+
+    fn discovery_config(&self, metric: Metric) -> serde_json::Value {
+        let mut config = json!({
+            "name": metric.name().replace('_', " "),
+            "unique_id": format!("{}_{}", self.config.client_id, metric.name()),
+            "state_topic": self.state_topic(metric),
+            "availability_topic": self.status_topic(),
+            "device": {
+                "identifiers": [self.config.client_id],
+                "name": self.config.client_id,
+                "model": "LED Weather Station",
+            },
+        });
+
+        if let Some(class) = device_class(metric) {
+            config["device_class"] = json!(class);
+        }
+        if metric != Metric::Comfort {
+            config["state_class"] = json!("measurement");
+        }
+        if !unit(metric).is_empty() {
+            config["unit_of_measurement"] = json!(unit(metric));
+        }
+
+        config
+    }
+}
+
+// Home Assistant's name for what a metric measures, if it has one
+fn device_class(metric: Metric) -> Option<&'static str> {
+    match metric {
+        Metric::Co2 | Metric::ECo2 => Some("carbon_dioxide"),
+        Metric::Temperature | Metric::DewPoint | Metric::HeatIndex => Some("temperature"),
+        Metric::Humidity => Some("humidity"),
+        Metric::Pressure => Some("atmospheric_pressure"),
+        Metric::Tvoc => Some("volatile_organic_compounds_parts"),
+        Metric::Light => Some("illuminance"),
+        Metric::AbsoluteHumidity | Metric::Humidex | Metric::Comfort => None,
+    }
+}
+
+// Units spelled the way Home Assistant expects them
+fn unit(metric: Metric) -> &'static str {
+    match metric {
+        Metric::Temperature | Metric::DewPoint | Metric::HeatIndex => "°C",
+        Metric::Humidity => "%",
+        _ => metric.unit(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rotary::InputEvent;
+    use std::sync::mpsc::channel;
+
+    fn publisher(config: MqttConfig) -> (Publisher, Receiver<Command>) {
+        let (tx, rx) = channel();
+        (Publisher::new(&config, tx), rx)
+    }
+
+    fn readings(values: &[(Metric, f32)]) -> Readings {
+        let mut readings = Readings::default();
+        for (metric, value) in values {
+            readings.set(*metric, *value);
+        }
+        readings
+    }
+
+    fn topics(publisher: &Publisher) -> Vec<&str> {
+        publisher.buffer.iter().map(|m| m.topic.as_str()).collect()
+    }
+
+    #[test]
+    fn discovery_config() {
+        let (publisher, _) = publisher(MqttConfig::default());
+
+        let co2 = publisher.discovery_config(Metric::Co2);
+        assert_eq!(co2["name"], "co2");
+        assert_eq!(co2["unique_id"], "leddy_co2");
+        assert_eq!(co2["state_topic"], "leddy/co2");
+        assert_eq!(co2["availability_topic"], "leddy/status");
+        assert_eq!(co2["device"]["identifiers"], json!(["leddy"]));
+        assert_eq!(co2["device_class"], "carbon_dioxide");
+        assert_eq!(co2["state_class"], "measurement");
+        assert_eq!(co2["unit_of_measurement"], "ppm");
+
+        let dew_point = publisher.discovery_config(Metric::DewPoint);
+        assert_eq!(dew_point["name"], "dew point");
+        assert_eq!(dew_point["device_class"], "temperature");
+        assert_eq!(dew_point["unit_of_measurement"], "°C");
+
+        // A category, with neither a unit nor a class
+        let comfort = publisher.discovery_config(Metric::Comfort);
+        assert!(comfort.get("device_class").is_none());
+        assert!(comfort.get("state_class").is_none());
+        assert!(comfort.get("unit_of_measurement").is_none());
+    }
+
+    #[test]
+    fn readings_are_announced_once() {
+        let (mut publisher, _) = publisher(MqttConfig::default());
+
+        publisher.queue(&readings(&[(Metric::Co2, 612.4), (Metric::Temperature, 21.46)]));
+        publisher.queue(&readings(&[(Metric::Co2, 615.0)]));
+        assert_eq!(
+            topics(&publisher),
+            [
+                "homeassistant/sensor/leddy/co2/config",
+                "leddy/co2",
+                "homeassistant/sensor/leddy/temperature/config",
+                "leddy/temperature",
+                "leddy/co2",
+            ]
+        );
+
+        let payloads: Vec<&str> = publisher.buffer.iter().map(|m| m.payload.as_str()).collect();
+        assert_eq!(payloads[1], "612");
+        assert_eq!(payloads[3], "21.5");
+        assert!(publisher.buffer[0].retain);
+    }
+
+    #[test]
+    fn comfort_is_published_by_label() {
+        let config = MqttConfig {
+            discovery: false,
+            ..MqttConfig::default()
+        };
+        let (mut publisher, _) = publisher(config);
+
+        publisher.queue(&readings(&[(Metric::Comfort, Comfort::Humid.value())]));
+        assert_eq!(topics(&publisher), ["leddy/comfort"]);
+        assert_eq!(publisher.buffer[0].payload, "humid");
+    }
+
+    #[test]
+    fn buffer_drops_the_oldest_while_disconnected() {
+        let config = MqttConfig {
+            discovery: false,
+            buffer: 3,
+            ..MqttConfig::default()
+        };
+        let (mut publisher, _) = publisher(config);
+
+        for co2 in [400.0, 500.0, 600.0, 700.0, 800.0] {
+            publisher.queue(&readings(&[(Metric::Co2, co2)]));
+        }
+        let payloads: Vec<&str> = publisher.buffer.iter().map(|m| m.payload.as_str()).collect();
+        assert_eq!(payloads, ["600", "700", "800"]);
+    }
+
+    #[test]
+    fn commands() {
+        let (publisher, rx) = publisher(MqttConfig::default());
+
+        publisher.command("leddy/command/brightness", b"40");
+        publisher.command("leddy/command/input", b"left");
+        publisher.command("leddy/command/screen", b" maze\n");
+        assert_eq!(rx.try_recv(), Ok(Command::Brightness(40)));
+        assert_eq!(rx.try_recv(), Ok(Command::Input(InputEvent::Left)));
+        assert_eq!(rx.try_recv(), Ok(Command::Screen("maze".to_string())));
+
+        // Invalid ones and other topics are ignored
+        publisher.command("leddy/command/brightness", b"140");
+        publisher.command("leddy/command/explode", b"");
+        publisher.command("other/command/brightness", b"40");
+        publisher.command("leddy/brightness", b"40");
+        assert!(rx.try_recv().is_err());
+    }
+}
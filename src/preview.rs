@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tiny_http::{Header, Request, Response, StatusCode};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+// The preview is for looking at, a few frames a second is plenty
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+static PAGE: &str = include_str!("preview.html");
+
+pub type SharedFrame = Arc<Mutex<Frame>>;
+
+// The last frame shown on the panel, as packed RGB rows
+pub struct Frame {
+    pub pixels: Vec<u8>,
+    pub number: u64, // bumped on every update, so unchanged frames are not resent
+}
+
+impl Frame {
+    pub fn shared(width: i32, height: i32) -> SharedFrame {
+        Arc::new(Mutex::new(Frame {
+            pixels: vec![0; (width * height * 3) as usize],
+            number: 0,
+        }))
+    }
+}
+
+pub fn page() -> Response<std::io::Cursor<Vec<u8>>> {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..])
+        .expect("valid header");
+    Response::from_string(PAGE).with_header(content_type)
+}
+
+// Upgrades the request to a WebSocket and streams frames to it as binary
+// messages until the browser goes away, from a thread of its own
+pub fn stream(
+    request: Request,
+    frame: SharedFrame,
+    cancel: Arc<AtomicBool>,
+) -> Option<JoinHandle<()>> {
+    let key = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Sec-WebSocket-Key"))
+        .map(|h| derive_accept_key(h.value.as_bytes()));
+
+    let key = match key {
+        Some(key) => key,
+        None => {
+            let response = Response::from_string("expected a WebSocket").with_status_code(400);
+            request.respond(response).ok();
+            return None;
+        }
+    };
+
+    let accept = Header::from_bytes(&b"Sec-WebSocket-Accept"[..], key.as_bytes()).expect("valid header");
+    let response = Response::empty(StatusCode(101)).with_header(accept);
+
+    Some(thread::spawn(move || {
+        let mut socket = WebSocket::from_raw_socket(request.upgrade("websocket", response), Role::Server, None);
+        let mut sent = None;
+
+        while !cancel.load(Ordering::Relaxed) {
+            let pixels = {
+                let frame = frame.lock().expect("lock preview frame");
+                if sent == Some(frame.number) {
+                    None
+                } else {
+                    sent = Some(frame.number);
+                    Some(frame.pixels.clone())
+                }
+            };
+
+            if let Some(pixels) = pixels {
+                if socket.send(Message::Binary(pixels)).is_err() {
+                    break;
+                }
+            }
+
+            thread::sleep(FRAME_INTERVAL);
+        }
+
+        socket.close(None).ok();
+    }))
+}
@@ -1,3 +1,4 @@
+use log::{debug, warn};
 use rppal::gpio::{Gpio, Level, Trigger};
 
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -16,7 +17,7 @@ pub struct RotaryEncoder {
     state: (Level, Level),
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum InputEvent {
     Left,
     Right,
@@ -32,12 +33,19 @@ enum Direction {
 }
 
 impl RotaryEncoder {
-    pub fn new(tx: Sender<InputEvent>) -> Self {
-        let gpio = Gpio::new().expect("acees gpio");
-        RotaryEncoder {
+    pub fn new(tx: Sender<InputEvent>) -> Result<Self, rppal::gpio::Error> {
+        let gpio = Gpio::new()?;
+        Ok(RotaryEncoder {
             gpio: gpio,
             tx: tx,
             state: (Level::Low, Level::Low),
+        })
+    }
+
+    fn send(&self, event: InputEvent) {
+        debug!("Input {:?}", event);
+        if self.tx.send(event).is_err() {
+            warn!("Dropped input {:?}, the display is gone", event);
         }
     }
 
@@ -63,7 +71,7 @@ impl RotaryEncoder {
                     *direction = Right;
                 } else if new_state == (Low, Low) {
                     if *direction == Left {
-                        self.tx.send(InputEvent::Left);
+                        self.send(InputEvent::Left);
                     }
                 }
             },
@@ -74,7 +82,7 @@ impl RotaryEncoder {
                     *direction = Left;
                 } else if new_state == (Low, Low) {
                     if *direction == Right {
-                        self.tx.send(InputEvent::Right);
+                        self.send(InputEvent::Right);
                     }
                 }
             },
@@ -89,9 +97,9 @@ impl RotaryEncoder {
                 // But we can use the Direction value to deduce where we came from
                 } else if new_state == (Low, Low) {
                     if *direction == Left {
-                        self.tx.send(InputEvent::Left);
+                        self.send(InputEvent::Left);
                     } else if *direction == Right {
-                        self.tx.send(InputEvent::Right);
+                        self.send(InputEvent::Right);
                     }
                 }
             }
@@ -101,7 +109,8 @@ impl RotaryEncoder {
     }
 
     pub fn poll_loop(&mut self, terminate: Arc<AtomicBool>) -> Result<(), rppal::gpio::Error> {
-        let timeout = Some(Duration::from_secs(1));
+        // Short, so shutting down does not have to wait on it
+        let timeout = Some(Duration::from_millis(100));
 
         let mut left = self.gpio.get(LEFT_PIN)?.into_input_pullup();
         left.set_interrupt(Trigger::Both);
@@ -131,13 +140,13 @@ impl RotaryEncoder {
                         while button.read() == Level::Low {
                             std::thread::sleep(Duration::from_millis(100));
                             if button_down.elapsed().as_millis() > 1000 {
-                                self.tx.send(InputEvent::LongPress);
+                                self.send(InputEvent::LongPress);
                                 sent = true;
                                 break;
                             }
                         }
                         if !sent {
-                            self.tx.send(InputEvent::Click);
+                            self.send(InputEvent::Click);
                         }
                     }
                     _ => {} // do nothing
@@ -1,168 +1,213 @@
 use chrono::Local;
 use embedded_graphics::prelude::*;
 use embedded_graphics::{
-    image::Image,
     mono_font::{iso_8859_1::FONT_6X10, MonoTextStyle},
     pixelcolor::Rgb888,
+    primitives::{PrimitiveStyle, Rectangle, Triangle},
     text::{Alignment, Text},
 };
-use image::io::Reader as ImageReader;
-use image::{imageops::FilterType, ImageOutputFormat};
-use linux_embedded_hal::Delay;
-use linux_embedded_hal::I2cdev;
-use sensor_scd30::Measurement;
-use sensor_scd30::Scd30;
-use std::collections::LinkedList;
-use std::io::Cursor;
-use std::thread;
-use std::time::Duration;
-use std::{
-    error::Error,
-    sync::{atomic::Ordering, Arc},
-};
-use tinybmp::DynamicBmp;
-
-use std::sync::atomic::AtomicBool;
+use image::ImageFormat;
+use serde_json::{json, Value};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
 static DEFAULT_BACKGROUND: &[u8] = include_bytes!("../../sakura-bg.bmp");
 
+use crate::config::BackgroundConfig;
+use crate::downloader::Download;
+use crate::error;
+use crate::history::{Direction, SharedHistory, STALE_AFTER};
+use crate::locale::Locale;
 use crate::mailbox::Mailbox;
+use crate::screens::{Canvas, Framebuffer};
+use crate::sensors::{Metric, Readings};
+
+// Readings the trend arrows are fitted over
+const TREND_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+// We estimate how long until CO2 gets this bad
+const CO2_TARGET: f32 = 1000.0;
+
+const CHAR_WIDTH: i32 = 6;
 
 pub struct BackgroundScreen {
-    buffers: LinkedList<Vec<u8>>,
-    rx: Mailbox<Measurement>,
-    default: DynamicBmp<'static, Rgb888>,
+    images: Vec<(usize, Framebuffer)>, // downloaded so far, by index in the configured sources
+    current: usize,                     // into `images`
+    wanted: Option<usize>,              // saved source to show once it has downloaded
+    downloads: Receiver<Download>,
+    rx: Mailbox<Readings>,
+    history: SharedHistory,
+    metrics: Option<Vec<Metric>>, // what to show, everything measured if not configured
+    locale: Locale,
+    default: Framebuffer,
     font_style: MonoTextStyle<'static, Rgb888>,
+    stale_style: MonoTextStyle<'static, Rgb888>,
     sensor_string: String,
+    glyphs: Vec<(usize, Direction)>, // trend arrows, by character position in sensor_string
+    last_reading: Option<Instant>,
+    started: Instant,
     clock_string: String,
+    date_string: Option<String>,
     render_state: (i32, i32),
 }
 
-fn sensor_thread(i2c: I2cdev, tx: Mailbox<Measurement>, cancel: Arc<AtomicBool>) {
-    let mut scd = Scd30::new(i2c, Delay {}).unwrap();
-
-    let pressure_compensation = 1004; // hPa
-    scd.set_measurement_interval(2).unwrap();
-    scd.start_continuous(pressure_compensation).unwrap();
-
-    while !cancel.load(Ordering::Relaxed) {
-        thread::sleep(Duration::from_secs(1));
-        if let Err(e) = scd.data_ready() {
-            eprintln!("E: {:?}", e);
-            continue;
-        }
-
-        match scd.read_data() {
-            Ok(m) => {
-                tx.put(m).expect("write measurement");
-            }
-            Err(e) => eprintln!("Polled {:?}", e),
-        }
-    }
-
-    scd.stop_continuous().expect("stop sensor");
-}
-
-fn fetch_background(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-    let body = minreq::get(url).send()?.into_bytes();
-
-    let orig = ImageReader::new(Cursor::new(body))
-        .with_guessed_format()?
-        .decode()?;
-    let resized = orig.resize_to_fill(64, 32, FilterType::Triangle);
-
-    let mut buffer = Vec::with_capacity((resized.width() * resized.height() * 3) as usize);
-    resized.write_to(&mut Cursor::new(&mut buffer), ImageOutputFormat::Bmp)?;
-
-    Ok(buffer)
-}
-
 impl BackgroundScreen {
-    pub fn new(term: Arc<AtomicBool>) -> Self {
-        let rx = Mailbox::new();
-        let i2c = I2cdev::new("/dev/i2c-1").expect("No i2c device");
-
-        let tx = rx.clone();
-        thread::spawn(move || {
-            sensor_thread(i2c, tx, term);
-        });
-
-        let src = [
-            "https://c4.wallpaperflare.com/wallpaper/765/580/971/digital-art-pixel-art-pixels-landscape-wallpaper-preview.jpg",
-            "https://c4.wallpaperflare.com/wallpaper/406/189/125/digital-art-pixel-art-pixelated-pixels-wallpaper-preview.jpg",
-            "https://wallpaperaccess.com/full/2122578.jpg",
-            //"https://art.pixilart.com/a72d2ef88ded5fd.png",    
-            //"https://hyperpad-forum.s3.amazonaws.com/assets/559e7b8c-f34a-4ab1-a326-d3e241ce76cd.png",
-        ];
-
-        let mut images = LinkedList::new();
-        for img in src {
-            match fetch_background(img) {
-                Ok(buffer) => images.push_back(buffer),
-                Err(e) => eprintln!("Background download err: `{}`", e),
-            }
-        }
-
-        let default = DynamicBmp::from_slice(&DEFAULT_BACKGROUND).expect("Parse bmp data");
+    pub fn new(
+        config: &BackgroundConfig,
+        locale: &Locale,
+        rx: Mailbox<Readings>,
+        history: SharedHistory,
+        downloads: Receiver<Download>,
+    ) -> Self {
+        let default =
+            Framebuffer::from_bytes(DEFAULT_BACKGROUND, ImageFormat::Bmp).expect("Parse bmp data");
         let font_style = MonoTextStyle::new(&FONT_6X10, Rgb888::WHITE);
+        let stale_style = MonoTextStyle::new(&FONT_6X10, Rgb888::new(90, 90, 90));
 
         BackgroundScreen {
-            buffers: images,
+            images: Vec::new(),
+            current: 0,
+            wanted: None,
+            downloads: downloads,
             rx: rx,
+            history: history,
+            metrics: config.metrics.clone(),
+            locale: locale.clone(),
             default: default,
             font_style: font_style,
+            stale_style: stale_style,
             sensor_string: "Loading...".to_string(),
+            glyphs: Vec::new(),
+            last_reading: None,
+            started: Instant::now(),
             clock_string: "HH:MM:SS".to_string(),
+            date_string: None,
             render_state: (0, 0),
         }
     }
 
     fn next(&mut self) {
-        if let Some(value) = self.buffers.pop_front() {
-            self.buffers.push_back(value);
+        if !self.images.is_empty() {
+            self.current = wrap(self.current, 1, self.images.len());
+            self.wanted = None;
         }
     }
 
     fn prev(&mut self) {
-        if let Some(value) = self.buffers.pop_back() {
-            self.buffers.push_front(value);
+        if !self.images.is_empty() {
+            self.current = wrap(self.current, -1, self.images.len());
+            self.wanted = None;
         }
     }
 
-    fn draw(&mut self, canvas: &mut rpi_led_matrix::LedCanvas) {
+    // Picks up what the downloader has finished, without changing what is shown
+    fn receive(&mut self) {
+        while let Ok((source, frame)) = self.downloads.try_recv() {
+            match self.images.binary_search_by_key(&source, |(s, _)| *s) {
+                Ok(i) => self.images[i].1 = frame, // refreshed
+                Err(i) => {
+                    if !self.images.is_empty() && i <= self.current {
+                        self.current += 1;
+                    }
+                    self.images.insert(i, (source, frame));
+                }
+            }
+
+            if self.wanted == Some(source) {
+                self.current = self.images.iter().position(|(s, _)| *s == source).unwrap_or(0);
+                self.wanted = None;
+            }
+        }
+    }
+
+    fn draw(&mut self, canvas: &mut Canvas) -> error::Result<()> {
         use std::fmt::Write; // allow write! into &mut String
 
-        if let Some(img) = self.buffers.front() {
-            let bmp = DynamicBmp::from_slice(img).unwrap();
+        self.receive();
+        let image = self.images.get(self.current).map(|(_, image)| image);
+        canvas.blit(image.unwrap_or(&self.default));
 
-            Image::new(&bmp, Point::new(0, 0))
-                .draw(canvas)
-                .expect("cannot draw background");
-        } else {
-            Image::new(&self.default, Point::new(0, 0))
-                .draw(canvas)
-                .expect("cannot draw background");
-        }
+        let mut formatted = Ok(());
 
-        self.rx.if_new(|m| {
-            if !m.co2.is_nan() {
+        self.rx.if_new(|readings| {
+            if !readings.is_empty() {
                 self.sensor_string.clear();
-
-                write!(
-                    &mut self.sensor_string,
-                    "Co2: {} ppm, T: {:.1} ºC, Hum: {} %RH",
-                    m.co2 as isize, m.temp, m.rh as isize
-                )
-                .expect("format sensor string");
+                self.glyphs.clear();
+                let history = self.history.lock().expect("lock history");
+
+                let shown: Vec<(Metric, f32)> = match &self.metrics {
+                    Some(metrics) => metrics
+                        .iter()
+                        .filter_map(|m| readings.get(*m).map(|v| (*m, v)))
+                        .collect(),
+                    None => readings.iter().filter(|(m, _)| !m.is_derived()).collect(),
+                };
+
+                for (metric, value) in shown {
+                    if !self.sensor_string.is_empty() {
+                        self.sensor_string.push_str(", ");
+                    }
+                    formatted = formatted.and(write!(
+                        &mut self.sensor_string,
+                        "{}: {}",
+                        metric.label(),
+                        self.locale.value_with_unit(metric, value)
+                    ));
+
+                    if metric == Metric::Comfort {
+                        continue; // a category, it has no trend
+                    }
+
+                    if let Some(trend) = history.trend(metric, TREND_WINDOW) {
+                        // Leave a blank character for the arrow to be drawn in
+                        self.sensor_string.push(' ');
+                        self.glyphs.push((self.sensor_string.chars().count(), trend.direction()));
+                        self.sensor_string.push(' ');
+
+                        if metric == Metric::Co2 && trend.direction() != Direction::Steady {
+                            let sign = if trend.per_minute > 0.0 { "+" } else { "" };
+                            formatted = formatted.and(write!(
+                                &mut self.sensor_string,
+                                " {}{}/min",
+                                sign,
+                                self.locale.number(trend.per_minute, 0)
+                            ));
+
+                            if trend.direction() == Direction::Rising {
+                                if let Some(minutes) = trend.minutes_until(CO2_TARGET) {
+                                    formatted = formatted.and(write!(
+                                        &mut self.sensor_string,
+                                        " ({} in {} min)",
+                                        self.locale.value(metric, CO2_TARGET),
+                                        self.locale.number(minutes, 0)
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                }
+                self.last_reading = Some(Instant::now());
             }
         });
+        formatted?;
+
+        // Old readings are greyed out, or shown as offline if we never had one
+        let stale = match self.last_reading {
+            Some(at) => at.elapsed() > STALE_AFTER,
+            None if self.started.elapsed() > STALE_AFTER => {
+                if self.sensor_string != "Sensor offline" {
+                    self.sensor_string = "Sensor offline".to_string();
+                    self.glyphs.clear();
+                }
+                true
+            }
+            None => false,
+        };
+        let sensor_style = if stale { self.stale_style } else { self.font_style };
 
-        self.clock_string.clear();
-        write!(
-            &mut self.clock_string,
-            "{}",
-            Local::now().format("%H:%M:%S")
-        );
+        let now = Local::now();
+        self.clock_string = self.locale.time(&now);
+        self.date_string = self.locale.date(&now);
 
         let (mut x, mut dx) = self.render_state;
 
@@ -176,9 +221,12 @@ impl BackgroundScreen {
             }
         }
 
-        Text::new(&self.sensor_string, Point::new(64 - x, 10), self.font_style)
-            .draw(canvas)
-            .expect("Could not draw");
+        Text::new(&self.sensor_string, Point::new(64 - x, 10), sensor_style).draw(canvas)?;
+
+        for (position, direction) in &self.glyphs {
+            let cell = Point::new(64 - x + *position as i32 * CHAR_WIDTH, 10);
+            draw_trend_glyph(canvas, cell, *direction, stale)?;
+        }
 
         Text::with_alignment(
             &self.clock_string,
@@ -186,14 +234,23 @@ impl BackgroundScreen {
             self.font_style,
             Alignment::Center,
         )
-        .draw(canvas)
-        .expect("Could not draw");
+        .draw(canvas)?;
+
+        if let Some(date) = &self.date_string {
+            Text::with_alignment(date, Point::new(32, 30), self.font_style, Alignment::Center)
+                .draw(canvas)?;
+        }
 
         self.render_state = (x, dx);
+        Ok(())
     }
 }
 
 impl crate::Screen for BackgroundScreen {
+    fn name(&self) -> &'static str {
+        "background"
+    }
+
     fn left(&mut self) {
         self.prev();
     }
@@ -206,9 +263,69 @@ impl crate::Screen for BackgroundScreen {
         // Do nothing
     }
 
-    fn draw(&mut self, canvas: &mut rpi_led_matrix::LedCanvas) {
-        self.draw(canvas);
+    fn draw(&mut self, canvas: &mut Canvas) -> error::Result<()> {
+        self.draw(canvas)
+    }
+
+    // Images are decoded up front, only the scrolling text can go wrong
+    fn reset(&mut self) {
+        self.sensor_string.clear();
+        self.glyphs.clear();
+        self.render_state = (0, 0);
+    }
+
+    // By source rather than position, images arrive in whatever order they download
+    fn save(&self) -> Option<Value> {
+        let source = self.images.get(self.current).map(|(s, _)| *s).or(self.wanted);
+        Some(json!({ "source": source }))
     }
+
+    fn restore(&mut self, state: &Value) {
+        self.wanted = state["source"].as_u64().map(|s| s as usize);
+    }
+}
+
+// A small arrow in the character cell whose baseline starts at `at`
+fn draw_trend_glyph(
+    canvas: &mut Canvas,
+    at: Point,
+    direction: Direction,
+    stale: bool,
+) -> error::Result<()> {
+    let color = match direction {
+        _ if stale => Rgb888::new(90, 90, 90),
+        Direction::Rising => Rgb888::new(255, 120, 0),
+        Direction::Falling => Rgb888::new(0, 170, 255),
+        Direction::Steady => Rgb888::new(180, 180, 180),
+    };
+    let style = PrimitiveStyle::with_fill(color);
+
+    let (top, bottom) = (at.y - 6, at.y - 2);
+    let left = at.x;
+    let right = at.x + 4;
+    let middle = at.x + 2;
+
+    let drawn = match direction {
+        Direction::Rising => Triangle::new(
+            Point::new(left, bottom),
+            Point::new(right, bottom),
+            Point::new(middle, top),
+        )
+        .into_styled(style)
+        .draw(canvas),
+        Direction::Falling => Triangle::new(
+            Point::new(left, top),
+            Point::new(right, top),
+            Point::new(middle, bottom),
+        )
+        .into_styled(style)
+        .draw(canvas),
+        Direction::Steady => Rectangle::new(Point::new(left, at.y - 4), Size::new(5, 1))
+            .into_styled(style)
+            .draw(canvas),
+    };
+    drawn?;
+    Ok(())
 }
 
 fn wrap(value: usize, delta: isize, size: usize) -> usize {
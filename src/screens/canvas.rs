@@ -0,0 +1,154 @@
+use embedded_graphics::{pixelcolor::Rgb888, prelude::*};
+use rpi_led_matrix::{LedCanvas, LedColor, LedMatrix};
+use std::convert::Infallible;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::Framebuffer;
+use crate::preview::{self, SharedFrame};
+
+pub const WIDTH: i32 = 64;
+pub const HEIGHT: i32 = 32;
+
+// Without a panel there is no vsync to wait for, this keeps the frame rate sane
+const SIMULATED_FRAME: Duration = Duration::from_millis(16);
+
+struct Panel {
+    matrix: LedMatrix,
+    canvas: Option<LedCanvas>, // only None while swapping
+}
+
+// What screens draw on. Everything goes through here, so the brightness can be
+// changed while running (the matrix only takes it at startup) and the frame
+// can be mirrored to the live preview. Without a matrix it only does the latter.
+pub struct Canvas {
+    panel: Option<Panel>,
+    brightness: u8, // percent
+    pixels: Vec<u8>,
+    preview: SharedFrame,
+    published: Instant,
+}
+
+impl Canvas {
+    pub fn new(matrix: Option<LedMatrix>, preview: SharedFrame) -> Self {
+        Canvas {
+            panel: matrix.map(|matrix| Panel {
+                canvas: Some(matrix.offscreen_canvas()),
+                matrix,
+            }),
+            brightness: 100,
+            pixels: vec![0; (WIDTH * HEIGHT * 3) as usize],
+            preview,
+            published: Instant::now(),
+        }
+    }
+
+    pub fn canvas_size(&self) -> (i32, i32) {
+        (WIDTH, HEIGHT)
+    }
+
+    pub fn clear(&mut self) {
+        if let Some(canvas) = self.led() {
+            canvas.clear();
+        }
+        self.pixels.fill(0);
+    }
+
+    pub fn set(&mut self, x: i32, y: i32, color: &LedColor) {
+        if x < 0 || y < 0 || x >= WIDTH || y >= HEIGHT {
+            return;
+        }
+
+        let dim = |c: u8| (c as u16 * self.brightness as u16 / 100) as u8;
+        let dimmed = LedColor {
+            red: dim(color.red),
+            green: dim(color.green),
+            blue: dim(color.blue),
+        };
+
+        let i = ((y * WIDTH + x) * 3) as usize;
+        self.pixels[i..i + 3].copy_from_slice(&[dimmed.red, dimmed.green, dimmed.blue]);
+
+        if let Some(canvas) = self.led() {
+            canvas.set(x, y, &dimmed);
+        }
+    }
+
+    // A whole frame at once, without going through `set` for every pixel
+    pub fn blit(&mut self, frame: &Framebuffer) {
+        self.pixels.copy_from_slice(frame.pixels());
+        if self.brightness < 100 {
+            for c in self.pixels.iter_mut() {
+                *c = (*c as u16 * self.brightness as u16 / 100) as u8;
+            }
+        }
+
+        // The matrix has no bulk update, only the mirror gets the copy
+        if let Some(canvas) = self.panel.as_mut().and_then(|panel| panel.canvas.as_mut()) {
+            for (i, rgb) in self.pixels.chunks_exact(3).enumerate() {
+                let color = LedColor {
+                    red: rgb[0],
+                    green: rgb[1],
+                    blue: rgb[2],
+                };
+                canvas.set(i as i32 % WIDTH, i as i32 / WIDTH, &color);
+            }
+        }
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    pub fn set_brightness(&mut self, percent: u8) {
+        self.brightness = percent.min(100);
+    }
+
+    // Shows what was drawn, the next frame starts from the other buffer
+    pub fn swap(&mut self) {
+        match &mut self.panel {
+            Some(panel) => {
+                let canvas = panel.canvas.take().expect("canvas is only taken while swapping");
+                panel.canvas = Some(panel.matrix.swap(canvas));
+            }
+            None => thread::sleep(SIMULATED_FRAME),
+        }
+
+        if self.published.elapsed() >= preview::FRAME_INTERVAL {
+            let mut frame = self.preview.lock().expect("lock preview frame");
+            frame.pixels.copy_from_slice(&self.pixels);
+            frame.number += 1;
+            self.published = Instant::now();
+        }
+    }
+
+    fn led(&mut self) -> Option<&mut LedCanvas> {
+        self.panel.as_mut().and_then(|panel| panel.canvas.as_mut())
+    }
+}
+
+impl OriginDimensions for Canvas {
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Canvas {
+    type Color = Rgb888;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let color = LedColor {
+                red: color.r(),
+                green: color.g(),
+                blue: color.b(),
+            };
+            self.set(point.x, point.y, &color);
+        }
+        Ok(())
+    }
+}
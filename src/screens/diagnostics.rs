@@ -0,0 +1,173 @@
+use embedded_graphics::prelude::*;
+use embedded_graphics::{
+    mono_font::{ascii::FONT_4X6, MonoTextStyle},
+    pixelcolor::Rgb888,
+    text::Text,
+};
+use log::error;
+use std::fmt::Write;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::locale::Locale;
+use crate::mailbox::Mailbox;
+use crate::sensors::{Metric, Scd30Command, Scd30Status};
+
+use super::{Canvas, Screen};
+
+// A forced recalibration needs a second click within this time
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Page {
+    Overview,
+    AutoCalibration,
+    ForcedRecalibration,
+}
+
+const PAGES: [Page; 3] = [Page::Overview, Page::AutoCalibration, Page::ForcedRecalibration];
+
+pub struct DiagnosticsScreen {
+    rx: Mailbox<Scd30Status>,
+    tx: Sender<Scd30Command>,
+    locale: Locale,
+    status: Option<Scd30Status>,
+    page: usize,
+    armed: Option<Instant>,
+    title_style: MonoTextStyle<'static, Rgb888>,
+    font_style: MonoTextStyle<'static, Rgb888>,
+    text: String,
+}
+
+impl DiagnosticsScreen {
+    pub fn new(locale: &Locale, rx: Mailbox<Scd30Status>, tx: Sender<Scd30Command>) -> Self {
+        Self {
+            rx,
+            tx,
+            locale: locale.clone(),
+            status: None,
+            page: 0,
+            armed: None,
+            title_style: MonoTextStyle::new(&FONT_4X6, Rgb888::new(255, 200, 0)),
+            font_style: MonoTextStyle::new(&FONT_4X6, Rgb888::WHITE),
+            text: String::new(),
+        }
+    }
+
+    fn send(&self, cmd: Scd30Command) {
+        if let Err(e) = self.tx.send(cmd) {
+            error!("Sensor is gone, dropped {:?}", e.0);
+        }
+    }
+
+    fn format_page(&mut self, status: &Scd30Status) -> &'static str {
+        let s = &status.settings;
+        self.text.clear();
+
+        match PAGES[self.page] {
+            Page::Overview => {
+                let _ = match status.firmware {
+                    Some(v) => writeln!(&mut self.text, "FW {}.{}", v >> 8, v & 0xff),
+                    None => writeln!(&mut self.text, "FW ?"),
+                };
+                writeln!(&mut self.text, "Int {} s", s.measurement_interval).ok();
+                let offset = s.temperature_offset.unwrap_or(0.0);
+                let offset = self.locale.convert_delta(Metric::Temperature, offset);
+                // The small font is plain ASCII, no degree sign
+                let unit = self.locale.unit(Metric::Temperature).trim_start_matches('º');
+                writeln!(&mut self.text, "T-off {} {}", self.locale.number(offset, 1), unit).ok();
+                let _ = match s.altitude {
+                    Some(alt) => writeln!(&mut self.text, "Alt {} m", alt),
+                    None => writeln!(&mut self.text, "P {} hPa", s.pressure),
+                };
+                "SCD30"
+            }
+            Page::AutoCalibration => {
+                let state = match s.auto_calibration {
+                    Some(true) => "on",
+                    Some(false) => "off",
+                    None => "default",
+                };
+                writeln!(&mut self.text, "ASC: {}", state).ok();
+                writeln!(&mut self.text, "Click to").ok();
+                writeln!(&mut self.text, "toggle").ok();
+                "Auto calib."
+            }
+            Page::ForcedRecalibration => {
+                writeln!(&mut self.text, "Ref {} ppm", s.calibration_reference).ok();
+                if let Some(ppm) = status.last_calibration {
+                    writeln!(&mut self.text, "Last {} ppm", ppm).ok();
+                }
+                if self.armed.is_some() {
+                    writeln!(&mut self.text, "Click again!").ok();
+                } else {
+                    writeln!(&mut self.text, "Click x2 to").ok();
+                    writeln!(&mut self.text, "calibrate").ok();
+                }
+                "Force calib."
+            }
+        }
+    }
+}
+
+impl Screen for DiagnosticsScreen {
+    fn name(&self) -> &'static str {
+        "diagnostics"
+    }
+
+    fn left(&mut self) {
+        self.armed = None;
+        self.page = (self.page + PAGES.len() - 1) % PAGES.len();
+    }
+
+    fn right(&mut self) {
+        self.armed = None;
+        self.page = (self.page + 1) % PAGES.len();
+    }
+
+    fn click(&mut self) {
+        let settings = match &self.status {
+            Some(status) => &status.settings,
+            None => return,
+        };
+
+        match PAGES[self.page] {
+            Page::Overview => {}
+            Page::AutoCalibration => {
+                let enabled = !settings.auto_calibration.unwrap_or(false);
+                self.send(Scd30Command::SetAutoCalibration(enabled));
+            }
+            Page::ForcedRecalibration => match self.armed {
+                Some(at) if at.elapsed() < CONFIRM_TIMEOUT => {
+                    self.send(Scd30Command::ForceRecalibration(settings.calibration_reference));
+                    self.armed = None;
+                }
+                _ => self.armed = Some(Instant::now()),
+            },
+        }
+    }
+
+    fn draw(&mut self, canvas: &mut Canvas) -> Result<()> {
+        self.rx.if_new(|status| self.status = Some(status)).ok();
+
+        if matches!(self.armed, Some(at) if at.elapsed() >= CONFIRM_TIMEOUT) {
+            self.armed = None;
+        }
+
+        let status = match self.status.take() {
+            Some(status) => status,
+            None => {
+                Text::new("No sensor", Point::new(1, 6), self.font_style).draw(canvas)?;
+                return Ok(());
+            }
+        };
+
+        let title = self.format_page(&status);
+        self.status = Some(status);
+
+        Text::new(title, Point::new(1, 6), self.title_style).draw(canvas)?;
+        Text::new(&self.text, Point::new(1, 13), self.font_style).draw(canvas)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+
+use super::{HEIGHT, WIDTH};
+use crate::error::{Error, Result};
+
+// An image decoded and resized to the panel once, as packed RGB rows, so
+// drawing it every frame is a copy instead of a decode
+#[derive(Clone)]
+pub struct Framebuffer {
+    pixels: Box<[u8]>,
+}
+
+impl Framebuffer {
+    // Crops to the panel's aspect ratio, then scales down
+    pub fn from_image(image: &DynamicImage) -> Self {
+        let resized = image.resize_to_fill(WIDTH as u32, HEIGHT as u32, FilterType::Triangle);
+        Framebuffer {
+            pixels: resized.to_rgb8().into_raw().into_boxed_slice(),
+        }
+    }
+
+    pub fn from_bytes(data: &[u8], format: ImageFormat) -> Result<Self> {
+        let image = image::load_from_memory_with_format(data, format)
+            .map_err(|e| Error::Image(e.to_string()))?;
+        Ok(Framebuffer::from_image(&image))
+    }
+
+    // Packed RGB rows as `pixels` gives them, None unless it is exactly one frame
+    pub fn from_pixels(pixels: Vec<u8>) -> Option<Self> {
+        if pixels.len() != (WIDTH * HEIGHT * 3) as usize {
+            return None;
+        }
+        Some(Framebuffer {
+            pixels: pixels.into_boxed_slice(),
+        })
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+}
@@ -0,0 +1,224 @@
+use chrono::Utc;
+use embedded_graphics::prelude::*;
+use embedded_graphics::{
+    mono_font::{ascii::FONT_4X6, MonoTextStyle},
+    pixelcolor::Rgb888,
+    text::{Alignment, Text},
+};
+use rpi_led_matrix::LedColor;
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::history::{Resolution, SharedHistory};
+use crate::locale::Locale;
+use crate::sensors::Metric;
+
+use super::{Canvas, Screen};
+
+const METRICS: [Metric; 3] = [Metric::Co2, Metric::Temperature, Metric::Humidity];
+
+const WINDOWS: [(&str, Duration, Resolution); 2] = [
+    ("1h", Duration::from_secs(60 * 60), Resolution::Raw),
+    ("24h", Duration::from_secs(24 * 60 * 60), Resolution::FiveMinutes),
+];
+
+// The history only changes every couple of seconds, no need to ask every frame
+const REFRESH: Duration = Duration::from_secs(1);
+
+const GRAPH_TOP: i32 = 7;
+
+const GOOD: LedColor = LedColor { red: 0, green: 160, blue: 0 };
+const MODERATE: LedColor = LedColor { red: 180, green: 140, blue: 0 };
+const POOR: LedColor = LedColor { red: 200, green: 0, blue: 0 };
+
+// Air quality and comfort bands
+fn band(metric: Metric, value: f32) -> LedColor {
+    match metric {
+        Metric::Co2 if value < 800.0 => GOOD,
+        Metric::Co2 if value < 1200.0 => MODERATE,
+        Metric::Co2 => POOR,
+        Metric::Temperature if (19.0..=24.0).contains(&value) => GOOD,
+        Metric::Temperature if (17.0..=26.0).contains(&value) => MODERATE,
+        Metric::Humidity if (30.0..=60.0).contains(&value) => GOOD,
+        Metric::Humidity if (20.0..=70.0).contains(&value) => MODERATE,
+        _ => POOR,
+    }
+}
+
+// Smallest range the y axis is stretched to, so noise doesn't look like a trend
+fn min_span(metric: Metric) -> f32 {
+    match metric {
+        Metric::Co2 => 200.0,
+        Metric::Temperature => 2.0,
+        _ => 10.0,
+    }
+}
+
+pub struct GraphScreen {
+    history: SharedHistory,
+    locale: Locale,
+    metric: usize,
+    window: usize,
+    columns: Vec<Option<f32>>,
+    range: (f32, f32),
+    current: Option<f32>,
+    refreshed: Option<Instant>,
+    font_style: MonoTextStyle<'static, Rgb888>,
+    axis_style: MonoTextStyle<'static, Rgb888>,
+    text: String,
+}
+
+impl GraphScreen {
+    pub fn new(canvas: &Canvas, locale: &Locale, history: SharedHistory) -> Self {
+        let (width, _) = canvas.canvas_size();
+
+        Self {
+            history,
+            locale: locale.clone(),
+            metric: 0,
+            window: 0,
+            columns: vec![None; width as usize],
+            range: (0.0, 1.0),
+            current: None,
+            refreshed: None,
+            font_style: MonoTextStyle::new(&FONT_4X6, Rgb888::WHITE),
+            axis_style: MonoTextStyle::new(&FONT_4X6, Rgb888::new(150, 150, 150)),
+            text: String::new(),
+        }
+    }
+
+    fn invalidate(&mut self) {
+        self.refreshed = None;
+    }
+
+    // Averages the history into one value per column
+    fn refresh(&mut self) {
+        let metric = METRICS[self.metric];
+        let (_, window, resolution) = WINDOWS[self.window];
+
+        let (series, latest) = {
+            let history = self.history.lock().expect("lock history");
+            (
+                history.series(metric, window, resolution),
+                history.latest(metric),
+            )
+        };
+
+        let width = self.columns.len();
+        let start = Utc::now().timestamp() - window.as_secs() as i64;
+        let mut sums = vec![(0.0, 0u32); width];
+
+        for entry in &series {
+            let offset = (entry.start.timestamp() - start).max(0) as u64;
+            let column = (offset * width as u64 / window.as_secs()) as usize;
+            if let Some(sum) = sums.get_mut(column) {
+                sum.0 += entry.mean * entry.count as f32;
+                sum.1 += entry.count;
+            }
+        }
+
+        self.columns = sums
+            .iter()
+            .map(|(sum, n)| if *n > 0 { Some(sum / *n as f32) } else { None })
+            .collect();
+
+        let values = self.columns.iter().flatten();
+        let low = values.clone().fold(f32::MAX, |a, b| a.min(*b));
+        let high = values.fold(f32::MIN, |a, b| a.max(*b));
+        let pad = (min_span(metric) - (high - low)).max(0.0) / 2.0;
+        self.range = (low - pad, high + pad);
+
+        self.current = latest.map(|l| l.mean);
+        self.refreshed = Some(Instant::now());
+    }
+
+    fn draw_axis_label(&mut self, canvas: &mut Canvas, value: f32, y: i32) -> Result<()> {
+        use std::fmt::Write;
+
+        self.text.clear();
+        let metric = METRICS[self.metric];
+        let converted = self.locale.convert(metric, value);
+        write!(&mut self.text, "{}", self.locale.number(converted, 0))?;
+        Text::new(&self.text, Point::new(0, y), self.axis_style).draw(canvas)?;
+        Ok(())
+    }
+
+    fn draw_graph(&self, canvas: &mut Canvas) {
+        let (_, height) = canvas.canvas_size();
+        let metric = METRICS[self.metric];
+        let (low, high) = self.range;
+        let rows = (height - GRAPH_TOP) as f32;
+
+        for (x, value) in self.columns.iter().enumerate() {
+            if let Some(value) = value {
+                let fraction = ((value - low) / (high - low)).clamp(0.0, 1.0);
+                let bar = 1 + (fraction * (rows - 1.0)) as i32;
+                let color = band(metric, *value);
+
+                for y in (height - bar)..height {
+                    canvas.set(x as i32, y, &color);
+                }
+            }
+        }
+    }
+}
+
+impl Screen for GraphScreen {
+    fn name(&self) -> &'static str {
+        "graph"
+    }
+
+    fn left(&mut self) {
+        self.metric = (self.metric + METRICS.len() - 1) % METRICS.len();
+        self.invalidate();
+    }
+
+    fn right(&mut self) {
+        self.metric = (self.metric + 1) % METRICS.len();
+        self.invalidate();
+    }
+
+    fn click(&mut self) {
+        self.window = (self.window + 1) % WINDOWS.len();
+        self.invalidate();
+    }
+
+    fn draw(&mut self, canvas: &mut Canvas) -> Result<()> {
+        use std::fmt::Write;
+
+        if !matches!(self.refreshed, Some(at) if at.elapsed() < REFRESH) {
+            self.refresh();
+        }
+
+        self.draw_graph(canvas);
+
+        if self.columns.iter().any(Option::is_some) {
+            let (low, high) = self.range;
+            let (_, height) = canvas.canvas_size();
+            self.draw_axis_label(canvas, high, GRAPH_TOP + 5)?;
+            self.draw_axis_label(canvas, low, height - 1)?;
+        }
+
+        let metric = METRICS[self.metric];
+        let (window_name, _, _) = WINDOWS[self.window];
+
+        self.text.clear();
+        write!(&mut self.text, "{} {}", metric.label(), window_name)?;
+        Text::new(&self.text, Point::new(0, 5), self.font_style).draw(canvas)?;
+
+        if let Some(current) = self.current {
+            self.text.clear();
+            write!(&mut self.text, "{}", self.locale.value(metric, current))?;
+
+            let color = band(metric, current);
+            let style = MonoTextStyle::new(&FONT_4X6, Rgb888::new(color.red, color.green, color.blue));
+            Text::with_alignment(&self.text, Point::new(63, 5), style, Alignment::Right)
+                .draw(canvas)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.invalidate();
+    }
+}
@@ -3,42 +3,56 @@ use std::collections::{VecDeque, HashMap};
 use maze_generator::ellers_algorithm::EllersGenerator;
 use maze_generator::growing_tree::GrowingTreeGenerator;
 use maze_generator::prims_algorithm::PrimsGenerator;
+use log::warn;
 use rand;
 use rand::{Rng, prelude::ThreadRng};
-use rpi_led_matrix::{LedCanvas, LedColor};
+use rpi_led_matrix::LedColor;
+use serde_json::{json, Value};
 
-use super::Screen;
+use super::{Canvas, Screen};
+use crate::error::{Error, Result};
 
 use maze_generator::prelude::*;
 use maze_generator::recursive_backtracking::RbGenerator;
 
 pub struct MazeScreen {
-    maze: Maze,
+    maze: Option<Maze>, // generated on the first draw, so a failure goes to the supervisor
+    size: (i32, i32),   // in fields, two pixels each
     queue: VecDeque<(Coordinates, Option<Direction>)>,
     visited: HashMap<Coordinates, Option<Direction>>,
     done: bool,
+    generator: Option<usize>, // index into GENERATORS, a random one for every maze if None
 }
 
+// Names for the generators built in `regenerate`, in the same order
+const GENERATORS: [&str; 4] = ["ellers", "backtracking", "prims", "growing_tree"];
+
 impl MazeScreen {
-    pub fn new(canvas: &LedCanvas) -> Self {
+    pub fn new(canvas: &Canvas) -> Self {
         let (width, height) = canvas.canvas_size();
 
-        let mut generator = RbGenerator::new(Some([13; 32]));
-        let maze = generator.generate(width/2, height/2).unwrap();
-
-        let mut queue = VecDeque::new();
-        queue.push_back((maze.start, None));
+        Self {
+            maze: None,
+            size: (width/2, height/2),
+            queue: VecDeque::new(),
+            visited: HashMap::new(),
+            done: false,
+            generator: None,
+        }
+    }
 
-        Self { maze, queue, visited: HashMap::new(), done: false }
+    // Always the same one, the random ones come after it
+    fn generate_first(&mut self) -> Result<()> {
+        let mut generator = RbGenerator::new(Some([13; 32]));
+        let maze = generator.generate(self.size.0, self.size.1).map_err(|e| Error::Maze(e.to_string()))?;
+        self.solve(maze);
+        Ok(())
     }
 
-    fn reset(&mut self, rng: &mut impl Rng) {
+    fn regenerate(&mut self, rng: &mut impl Rng) -> Result<()> {
         use rand::prelude::SliceRandom;
 
-        self.visited.clear();
-        self.done = false;
-
-        let (width, height) = self.maze.size;
+        let (width, height) = self.size;
         let seed = Some(rng.gen());
 
         let mut generators = [
@@ -48,22 +62,35 @@ impl MazeScreen {
             &mut GrowingTreeGenerator::new(seed) as &mut dyn Generator,
         ];
 
-        self.maze = generators.choose_mut(rng).unwrap().generate(width, height).unwrap();
+        let generator = match self.generator {
+            Some(index) => &mut generators[index],
+            None => generators.choose_mut(rng).expect("there are generators to choose from"),
+        };
+        let maze = generator.generate(width, height).map_err(|e| Error::Maze(e.to_string()))?;
+        self.solve(maze);
+        Ok(())
+    }
 
-        self.queue.push_back((self.maze.start, None));
+    // Starts solving `maze` from the beginning
+    fn solve(&mut self, maze: Maze) {
+        self.visited.clear();
+        self.queue.clear();
+        self.queue.push_back((maze.start, None));
+        self.done = false;
+        self.maze = Some(maze);
     }
 
-    fn draw_maze(&mut self, canvas: &mut LedCanvas) {
+    fn draw_maze(&self, maze: &Maze, canvas: &mut Canvas) {
 
         let outline = LedColor {red: 120, green: 120, blue: 120 };
-        let (w, h) = self.maze.size;
+        let (w, h) = maze.size;
         for y in 0..h {
             for x in 0..w {
-                if let Some(f) = self.maze.get_field(&(x, y).into()) {
+                if let Some(f) = maze.get_field(&(x, y).into()) {
                     canvas.set(x*2, y*2, &outline);
 
                     for d in Direction::all() {
-                        if f.has_passage(&d) && self.maze.get_field(&f.coordinates.next(&d)).is_some() {
+                        if f.has_passage(&d) && maze.get_field(&f.coordinates.next(&d)).is_some() {
                             let (dx, dy) = match d {
                                 Direction::North => (0, -1),
                                 Direction::East => (1, 0),
@@ -96,39 +123,64 @@ impl MazeScreen {
         }
 
         //canvas.set(self.maze.start.x*2, self.maze.start.y*2, &start);
-        canvas.set(self.maze.goal.x*2, self.maze.goal.y*2, &goal);
+        canvas.set(maze.goal.x*2, maze.goal.y*2, &goal);
 
     }
 }
 
 
 impl Screen for MazeScreen {
+    fn name(&self) -> &'static str {
+        "maze"
+    }
+
+    // Turning picks the generator for the next maze, with random in between the last and first
     fn left(&mut self) {
+        self.generator = match self.generator {
+            None => Some(GENERATORS.len() - 1),
+            Some(0) => None,
+            Some(index) => Some(index - 1),
+        };
     }
 
     fn right(&mut self) {
+        self.generator = match self.generator {
+            None => Some(0),
+            Some(index) if index + 1 == GENERATORS.len() => None,
+            Some(index) => Some(index + 1),
+        };
     }
 
     fn click(&mut self) {
         let mut rng = rand::thread_rng();
-        self.reset(&mut rng);
+        // On failure the old maze stays, which is fine for a click
+        if let Err(e) = self.regenerate(&mut rng) {
+            warn!("{}", e);
+        }
     }
 
-    fn draw(&mut self, canvas: &mut LedCanvas) {
+    fn draw(&mut self, canvas: &mut Canvas) -> Result<()> {
         let mut rng = rand::thread_rng();
 
-        if self.done {
-            std::thread::sleep(std::time::Duration::from_millis(400));
-            self.reset(&mut rng);
-        } else if self.visited.contains_key(&self.maze.goal) {
-            self.done = true;
+        match &self.maze {
+            None => self.generate_first()?,
+            Some(_) if self.done => {
+                std::thread::sleep(std::time::Duration::from_millis(400));
+                self.regenerate(&mut rng)?;
+            }
+            Some(maze) if self.visited.contains_key(&maze.goal) => self.done = true,
+            Some(_) => {}
         }
 
-        self.draw_maze(canvas);
+        let maze = match &self.maze {
+            Some(maze) => maze,
+            None => return Ok(()),
+        };
+        self.draw_maze(maze, canvas);
 
         if let Some((to_explore, way_in)) = self.queue.pop_front() {
 
-            if let Some(field) = self.maze.get_field(&to_explore) {
+            if let Some(field) = maze.get_field(&to_explore) {
                 self.visited.insert(field.coordinates, way_in);
 
                 for d in Direction::gen_random_order(&mut rng) {
@@ -142,6 +194,24 @@ impl Screen for MazeScreen {
                 }
             }
         }
-        
+        Ok(())
+    }
+
+    // Solves the current maze again, generating it is what can fail
+    fn reset(&mut self) {
+        if let Some(maze) = self.maze.take() {
+            self.solve(maze);
+        }
     }
-}
\ No newline at end of file
+
+    fn save(&self) -> Option<Value> {
+        let generator = self.generator.map_or("random", |index| GENERATORS[index]);
+        Some(json!({ "generator": generator }))
+    }
+
+    fn restore(&mut self, state: &Value) {
+        if let Some(name) = state["generator"].as_str() {
+            self.generator = GENERATORS.iter().position(|g| *g == name);
+        }
+    }
+}
@@ -1,16 +1,54 @@
-use rpi_led_matrix::LedCanvas;
+use embedded_graphics::{
+    pixelcolor::Rgb888,
+    prelude::*,
+    primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, Styled},
+};
+use serde_json::Value;
+
+use crate::error::Result;
 
 pub trait Screen {
+    // Used to pick the screen by name from outside, e.g. over MQTT
+    fn name(&self) -> &'static str;
     fn left(&mut self);
     fn right(&mut self);
     fn click(&mut self);
-    fn draw(&mut self, canvas: &mut LedCanvas);
+    fn draw(&mut self, canvas: &mut Canvas) -> Result<()>;
+
+    // Called after `draw` failed, to get back to a state that can be drawn
+    fn reset(&mut self) {}
+
+    // What the user changed on the screen, kept across restarts
+    fn save(&self) -> Option<Value> {
+        None
+    }
+    fn restore(&mut self, _state: &Value) {}
+}
+
+// Outline around the whole panel
+pub fn border(color: Rgb888, width: u32) -> Styled<Rectangle, PrimitiveStyle<Rgb888>> {
+    let style = PrimitiveStyleBuilder::new()
+        .stroke_color(color)
+        .stroke_width(width)
+        .build();
+
+    Rectangle::new(Point::new(0, 0), Size::new(64, 32)).into_styled(style)
 }
 
+mod canvas;
+mod framebuffer;
+
 mod background;
 mod waves;
 mod maze;
+mod diagnostics;
+mod graph;
+
+pub use canvas::{Canvas, HEIGHT, WIDTH};
+pub use framebuffer::Framebuffer;
 
 pub use background::BackgroundScreen;
 pub use waves::WaveScreen;
-pub use maze::MazeScreen;
\ No newline at end of file
+pub use maze::MazeScreen;
+pub use diagnostics::DiagnosticsScreen;
+pub use graph::GraphScreen;
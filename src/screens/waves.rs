@@ -1,5 +1,9 @@
 use rand::prelude::*;
-use rpi_led_matrix::{LedCanvas, LedColor};
+use rpi_led_matrix::LedColor;
+use serde_json::{json, Value};
+
+use super::Canvas;
+use crate::error::Result;
 
 fn xy_to_index(width: i32, x: i32, y: i32) -> usize {
     assert!(width > 0);
@@ -44,7 +48,7 @@ fn shift_color(c: (f32, f32, f32), hue: f32) -> LedColor {
 }
 
 impl WaveScreen {
-    pub fn new(canvas: &LedCanvas) -> Self {
+    pub fn new(canvas: &Canvas) -> Self {
         let (width, height) = canvas.canvas_size();
         let map = (0..(width * height)).map(|_| rand::random()).collect();
 
@@ -61,7 +65,7 @@ impl WaveScreen {
             .collect();
     }
 
-    fn draw_pixels(&self, canvas: &mut LedCanvas) {
+    fn draw_pixels(&self, canvas: &mut Canvas) {
         let (width, height) = canvas.canvas_size();
         let map = &self.current_map;
         for y in 0..height {
@@ -81,7 +85,7 @@ impl WaveScreen {
         }
     }
 
-    pub fn draw(&mut self, canvas: &mut LedCanvas) {
+    pub fn draw(&mut self, canvas: &mut Canvas) {
         std::mem::swap(&mut self.current_map, &mut self.last_map);
 
         let (width, height) = canvas.canvas_size();
@@ -131,6 +135,10 @@ impl WaveScreen {
 }
 
 impl crate::Screen for WaveScreen {
+    fn name(&self) -> &'static str {
+        "waves"
+    }
+
     fn left(&mut self) {
         self.hue -= 0.1;
     }
@@ -143,7 +151,22 @@ impl crate::Screen for WaveScreen {
         self.reset();
     }
 
-    fn draw(&mut self, canvas: &mut LedCanvas) {
+    fn draw(&mut self, canvas: &mut Canvas) -> Result<()> {
         self.draw(canvas);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.reset();
+    }
+
+    fn save(&self) -> Option<Value> {
+        Some(json!({ "hue": self.hue }))
+    }
+
+    fn restore(&mut self, state: &Value) {
+        if let Some(hue) = state["hue"].as_f64() {
+            self.hue = hue as f32;
+        }
     }
 }
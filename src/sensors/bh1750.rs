@@ -0,0 +1,88 @@
+use embedded_hal::blocking::i2c::{Read, Write};
+use std::fmt::Debug;
+
+use super::{Driver, Metric, Readings};
+
+const POWER_ON: u8 = 0x01;
+const CONTINUOUS_HIGH_RES: u8 = 0x10;
+
+// Ambient light sensor, it has no id register so a powered on ACK is all we get
+pub struct Bh1750<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C, E> Bh1750<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+    E: Debug,
+{
+    pub fn probe(mut i2c: I2C, address: u8) -> Result<Self, String> {
+        i2c.write(address, &[POWER_ON])
+            .map_err(|e| format!("power on: {:?}", e))?;
+        i2c.write(address, &[CONTINUOUS_HIGH_RES])
+            .map_err(|e| format!("set mode: {:?}", e))?;
+
+        Ok(Bh1750 { i2c, address })
+    }
+}
+
+impl<I2C, E> Driver for Bh1750<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+    E: Debug,
+{
+    fn name(&self) -> &'static str {
+        "BH1750"
+    }
+
+    fn metrics(&self) -> &'static [Metric] {
+        &[Metric::Light]
+    }
+
+    fn poll(&mut self, readings: &mut Readings) -> Result<(), String> {
+        let mut buf = [0u8; 2];
+        self.i2c
+            .read(self.address, &mut buf)
+            .map_err(|e| format!("{:?}", e))?;
+
+        readings.set(Metric::Light, u16::from_be_bytes(buf) as f32 / 1.2);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal_mock::i2c::{Mock, Transaction};
+    use embedded_hal_mock::MockError;
+    use std::io::ErrorKind;
+
+    const ADDRESS: u8 = 0x23;
+
+    #[test]
+    fn probes_and_measures() {
+        let mut bus = Mock::new(&[
+            Transaction::write(ADDRESS, vec![POWER_ON]),
+            Transaction::write(ADDRESS, vec![CONTINUOUS_HIGH_RES]),
+            Transaction::read(ADDRESS, vec![0x01, 0x2c]),
+        ]);
+
+        let mut sensor = Bh1750::probe(bus.clone(), ADDRESS).expect("probe");
+        let mut readings = Readings::default();
+        sensor.poll(&mut readings).expect("poll");
+
+        assert!((readings.get(Metric::Light).unwrap() - 250.0).abs() < 0.01);
+        bus.done();
+    }
+
+    #[test]
+    fn nothing_at_the_address() {
+        let mut bus = Mock::new(&[
+            Transaction::write(ADDRESS, vec![POWER_ON]).with_error(MockError::Io(ErrorKind::Other))
+        ]);
+
+        assert!(Bh1750::probe(bus.clone(), ADDRESS).is_err());
+        bus.done();
+    }
+}
@@ -0,0 +1,254 @@
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use std::fmt::Debug;
+
+use super::{Driver, Metric, Readings};
+
+const REG_CHIP_ID: u8 = 0xd0;
+const REG_CALIB_00: u8 = 0x88;
+const REG_CALIB_26: u8 = 0xe1;
+const REG_CTRL_HUM: u8 = 0xf2;
+const REG_CTRL_MEAS: u8 = 0xf4;
+const REG_CONFIG: u8 = 0xf5;
+const REG_DATA: u8 = 0xf7;
+
+const CHIP_ID: u8 = 0x60;
+
+// Oversampling x1 everywhere, normal mode with 1 s standby
+const CTRL_HUM: u8 = 0b001;
+const CTRL_MEAS: u8 = 0b001_001_11;
+const CONFIG: u8 = 0b101_000_00;
+
+// Factory trimming parameters, see section 4.2.2 of the datasheet
+struct Calibration {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p: [f64; 9],
+    h1: f64,
+    h2: f64,
+    h3: f64,
+    h4: f64,
+    h5: f64,
+    h6: f64,
+}
+
+impl Calibration {
+    fn parse(a: &[u8; 26], b: &[u8; 7]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([a[i], a[i + 1]]) as f64;
+        let i16_at = |i: usize| i16::from_le_bytes([a[i], a[i + 1]]) as f64;
+
+        let mut p = [0.0; 9];
+        p[0] = u16_at(6);
+        for (n, value) in p.iter_mut().enumerate().skip(1) {
+            *value = i16_at(6 + n * 2);
+        }
+
+        // h4 and h5 are 12 bit signed values sharing a nibble
+        let h4 = ((b[3] as i8 as i16) << 4) | (b[4] & 0x0f) as i16;
+        let h5 = ((b[5] as i8 as i16) << 4) | (b[4] >> 4) as i16;
+
+        Calibration {
+            t1: u16_at(0),
+            t2: i16_at(2),
+            t3: i16_at(4),
+            p,
+            h1: a[25] as f64,
+            h2: i16::from_le_bytes([b[0], b[1]]) as f64,
+            h3: b[2] as f64,
+            h4: h4 as f64,
+            h5: h5 as f64,
+            h6: b[6] as i8 as f64,
+        }
+    }
+
+    // Floating point compensation formulas from section 8.1 of the datasheet.
+    // Returns (ºC, hPa, %RH).
+    fn compensate(&self, adc_t: f64, adc_p: f64, adc_h: f64) -> (f64, f64, f64) {
+        let p = &self.p;
+
+        let var1 = (adc_t / 16384.0 - self.t1 / 1024.0) * self.t2;
+        let var2 = (adc_t / 131072.0 - self.t1 / 8192.0).powi(2) * self.t3;
+        let t_fine = var1 + var2;
+        let temperature = t_fine / 5120.0;
+
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * p[5] / 32768.0;
+        var2 += var1 * p[4] * 2.0;
+        var2 = var2 / 4.0 + p[3] * 65536.0;
+        var1 = (p[2] * var1 * var1 / 524288.0 + p[1] * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * p[0];
+        let pressure = if var1 == 0.0 {
+            0.0
+        } else {
+            let mut pa = 1048576.0 - adc_p;
+            pa = (pa - var2 / 4096.0) * 6250.0 / var1;
+            let var1 = p[8] * pa * pa / 2147483648.0;
+            let var2 = pa * p[7] / 32768.0;
+            pa + (var1 + var2 + p[6]) / 16.0
+        };
+
+        let mut h = t_fine - 76800.0;
+        h = (adc_h - (self.h4 * 64.0 + self.h5 / 16384.0 * h))
+            * (self.h2 / 65536.0
+                * (1.0 + self.h6 / 67108864.0 * h * (1.0 + self.h3 / 67108864.0 * h)));
+        h *= 1.0 - self.h1 * h / 524288.0;
+        let humidity = h.clamp(0.0, 100.0);
+
+        (temperature, pressure / 100.0, humidity)
+    }
+}
+
+pub struct Bme280<I2C> {
+    i2c: I2C,
+    address: u8,
+    calibration: Calibration,
+}
+
+impl<I2C, E> Bme280<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    pub fn probe(mut i2c: I2C, address: u8) -> Result<Self, String> {
+        let mut id = [0u8];
+        i2c.write_read(address, &[REG_CHIP_ID], &mut id)
+            .map_err(|e| format!("read id: {:?}", e))?;
+        if id[0] != CHIP_ID {
+            return Err(format!("unexpected chip id {:#04x}", id[0]));
+        }
+
+        let mut a = [0u8; 26];
+        let mut b = [0u8; 7];
+        i2c.write_read(address, &[REG_CALIB_00], &mut a)
+            .and_then(|_| i2c.write_read(address, &[REG_CALIB_26], &mut b))
+            .map_err(|e| format!("read calibration: {:?}", e))?;
+
+        // ctrl_hum only takes effect after a write to ctrl_meas
+        let setup = [
+            (REG_CTRL_HUM, CTRL_HUM),
+            (REG_CONFIG, CONFIG),
+            (REG_CTRL_MEAS, CTRL_MEAS),
+        ];
+        for (reg, value) in setup {
+            i2c.write(address, &[reg, value])
+                .map_err(|e| format!("configure: {:?}", e))?;
+        }
+
+        Ok(Bme280 {
+            i2c,
+            address,
+            calibration: Calibration::parse(&a, &b),
+        })
+    }
+}
+
+impl<I2C, E> Driver for Bme280<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    fn name(&self) -> &'static str {
+        "BME280"
+    }
+
+    fn metrics(&self) -> &'static [Metric] {
+        &[Metric::Temperature, Metric::Humidity, Metric::Pressure]
+    }
+
+    fn poll(&mut self, readings: &mut Readings) -> Result<(), String> {
+        let mut d = [0u8; 8];
+        self.i2c
+            .write_read(self.address, &[REG_DATA], &mut d)
+            .map_err(|e| format!("{:?}", e))?;
+
+        let adc_p = ((d[0] as u32) << 12) | ((d[1] as u32) << 4) | (d[2] as u32 >> 4);
+        let adc_t = ((d[3] as u32) << 12) | ((d[4] as u32) << 4) | (d[5] as u32 >> 4);
+        let adc_h = ((d[6] as u32) << 8) | d[7] as u32;
+
+        let (t, p, h) = self
+            .calibration
+            .compensate(adc_t as f64, adc_p as f64, adc_h as f64);
+
+        readings.set(Metric::Temperature, t as f32);
+        readings.set(Metric::Pressure, p as f32);
+        readings.set(Metric::Humidity, h as f32);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal_mock::i2c::{Mock, Transaction};
+    use embedded_hal_mock::MockError;
+    use std::io::ErrorKind;
+
+    const ADDRESS: u8 = 0x76;
+
+    // The BMP280 datasheet's worked example (section 3.12), plus humidity trimming
+    const CALIB_00: [u8; 26] = [
+        0x70, 0x6b, 0x43, 0x67, 0x18, 0xfc, 0x7d, 0x8e, 0x43, 0xd6, 0xd0, 0x0b, 0x27, 0x0b,
+        0x8c, 0x00, 0xf9, 0xff, 0x8c, 0x3c, 0xf8, 0xc6, 0x70, 0x17, 0x00, 0x4b,
+    ];
+    const CALIB_26: [u8; 7] = [0x6a, 0x01, 0x00, 0x13, 0x29, 0x03, 0x1e];
+
+    fn probe_transactions() -> Vec<Transaction> {
+        vec![
+            Transaction::write_read(ADDRESS, vec![REG_CHIP_ID], vec![CHIP_ID]),
+            Transaction::write_read(ADDRESS, vec![REG_CALIB_00], CALIB_00.to_vec()),
+            Transaction::write_read(ADDRESS, vec![REG_CALIB_26], CALIB_26.to_vec()),
+            Transaction::write(ADDRESS, vec![REG_CTRL_HUM, CTRL_HUM]),
+            Transaction::write(ADDRESS, vec![REG_CONFIG, CONFIG]),
+            Transaction::write(ADDRESS, vec![REG_CTRL_MEAS, CTRL_MEAS]),
+        ]
+    }
+
+    #[test]
+    fn probes_and_compensates() {
+        let mut expectations = probe_transactions();
+        // adc_P = 415148, adc_T = 519888, adc_H = 30000
+        expectations.push(Transaction::write_read(
+            ADDRESS,
+            vec![REG_DATA],
+            vec![0x65, 0x5a, 0xc0, 0x7e, 0xed, 0x00, 0x75, 0x30],
+        ));
+        let mut bus = Mock::new(&expectations);
+
+        let mut sensor = Bme280::probe(bus.clone(), ADDRESS).expect("probe");
+        let mut readings = Readings::default();
+        sensor.poll(&mut readings).expect("poll");
+
+        let temperature = readings.get(Metric::Temperature).unwrap();
+        let pressure = readings.get(Metric::Pressure).unwrap();
+        let humidity = readings.get(Metric::Humidity).unwrap();
+        assert!((temperature - 25.08).abs() < 0.01, "{}", temperature);
+        assert!((pressure - 1006.53).abs() < 0.01, "{}", pressure);
+        assert!((humidity - 55.0).abs() < 0.01, "{}", humidity);
+        bus.done();
+    }
+
+    #[test]
+    fn rejects_other_chips() {
+        // A BMP280 answers at the same addresses, but has no humidity
+        let mut bus = Mock::new(&[Transaction::write_read(ADDRESS, vec![REG_CHIP_ID], vec![0x58])]);
+
+        assert!(Bme280::probe(bus.clone(), ADDRESS).is_err());
+        bus.done();
+    }
+
+    #[test]
+    fn poll_reports_bus_errors() {
+        let mut expectations = probe_transactions();
+        expectations.push(
+            Transaction::write_read(ADDRESS, vec![REG_DATA], vec![0; 8])
+                .with_error(MockError::Io(ErrorKind::Other)),
+        );
+        let mut bus = Mock::new(&expectations);
+
+        let mut sensor = Bme280::probe(bus.clone(), ADDRESS).expect("probe");
+        let mut readings = Readings::default();
+        assert!(sensor.poll(&mut readings).is_err());
+        assert!(readings.is_empty());
+        bus.done();
+    }
+}
@@ -0,0 +1,218 @@
+use super::{Metric, Readings};
+
+// Magnus formula coefficients (Sonntag 1990), good for -45 to 60 ºC
+const MAGNUS_A: f32 = 17.62;
+const MAGNUS_B: f32 = 243.12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comfort {
+    Dry,
+    Comfortable,
+    Humid,
+    Muggy,
+}
+
+impl Comfort {
+    // Too dry goes by relative humidity, the rest by how it feels, which is the dew point
+    pub fn classify(humidity: f32, dew_point: f32) -> Self {
+        if humidity < 30.0 {
+            Comfort::Dry
+        } else if dew_point < 16.0 {
+            Comfort::Comfortable
+        } else if dew_point < 20.0 {
+            Comfort::Humid
+        } else {
+            Comfort::Muggy
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Comfort::Dry => "dry",
+            Comfort::Comfortable => "comfortable",
+            Comfort::Humid => "humid",
+            Comfort::Muggy => "muggy",
+        }
+    }
+
+    // Comfort travels through the pipeline as a plain number like everything else
+    pub fn value(&self) -> f32 {
+        *self as u8 as f32
+    }
+
+    pub fn from_value(value: f32) -> Self {
+        match value as u8 {
+            0 => Comfort::Dry,
+            1 => Comfort::Comfortable,
+            2 => Comfort::Humid,
+            _ => Comfort::Muggy,
+        }
+    }
+}
+
+// ºC
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    let gamma = (humidity / 100.0).ln() + MAGNUS_A * temperature / (MAGNUS_B + temperature);
+    MAGNUS_B * gamma / (MAGNUS_A - gamma)
+}
+
+// g/m³
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    let saturation = 6.112 * (MAGNUS_A * temperature / (MAGNUS_B + temperature)).exp(); // hPa
+    saturation * humidity * 2.1674 / (273.15 + temperature)
+}
+
+// ºC, the US National Weather Service heat index (Rothfusz regression with
+// its low and high humidity adjustments). Below ~27 ºC it is close to the
+// temperature itself.
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let hi = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.04901523 * t + 10.14333127 * rh
+            - 0.22475541 * t * rh
+            - 0.00683783 * t * t
+            - 0.05481717 * rh * rh
+            + 0.00122874 * t * t * rh
+            + 0.00085282 * t * rh * rh
+            - 0.00000199 * t * t * rh * rh;
+
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+        }
+        hi
+    };
+
+    (hi - 32.0) * 5.0 / 9.0
+}
+
+// The Canadian humidex, unitless but on the same scale as ºC
+pub fn humidex(temperature: f32, dew_point: f32) -> f32 {
+    let vapour_pressure = 6.11 * (5417.7530 * (1.0 / 273.16 - 1.0 / (273.15 + dew_point))).exp();
+    temperature + 0.5555 * (vapour_pressure - 10.0)
+}
+
+// Fills in every derived metric we have inputs for, and removes the ones we don't
+pub fn derive(readings: &mut Readings) {
+    let inputs = (
+        readings.get(Metric::Temperature),
+        readings.get(Metric::Humidity),
+    );
+
+    match inputs {
+        (Some(t), Some(rh)) if rh > 0.0 => {
+            let dp = dew_point(t, rh);
+            readings.set(Metric::DewPoint, dp);
+            readings.set(Metric::AbsoluteHumidity, absolute_humidity(t, rh));
+            readings.set(Metric::HeatIndex, heat_index(t, rh));
+            readings.set(Metric::Humidex, humidex(t, dp));
+            readings.set(Metric::Comfort, Comfort::classify(rh, dp).value());
+        }
+        _ => {
+            for metric in Metric::ALL.iter().filter(|m| m.is_derived()) {
+                readings.remove(*metric);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn dew_point_matches_the_tables() {
+        // (ºC, %RH, dew point ºC)
+        for (t, rh, expected) in [(20.0, 50.0, 9.3), (25.0, 60.0, 16.7), (30.0, 80.0, 26.2), (10.0, 100.0, 10.0)] {
+            assert_near(dew_point(t, rh), expected, 0.1);
+        }
+    }
+
+    #[test]
+    fn absolute_humidity_matches_the_tables() {
+        // Half and all of the saturation vapour density, g/m³
+        for (t, rh, expected) in [(20.0, 50.0, 8.65), (25.0, 100.0, 23.0), (30.0, 100.0, 30.4)] {
+            assert_near(absolute_humidity(t, rh), expected, 0.2);
+        }
+    }
+
+    #[test]
+    fn heat_index_matches_the_nws_table() {
+        // (ºF, %RH, heat index ºF), the table is rounded to whole degrees
+        let table = [
+            (80.0, 40.0, 80.0),
+            (84.0, 90.0, 98.0),
+            (86.0, 90.0, 105.0),
+            (90.0, 50.0, 95.0),
+            (96.0, 65.0, 121.0),
+            (100.0, 40.0, 109.0),
+        ];
+        for (f, rh, expected) in table {
+            let celsius = (f - 32.0) * 5.0 / 9.0;
+            let fahrenheit = heat_index(celsius, rh) * 9.0 / 5.0 + 32.0;
+            assert_near(fahrenheit, expected, 1.0);
+        }
+    }
+
+    #[test]
+    fn heat_index_is_the_temperature_when_cool() {
+        assert_near(heat_index(20.0, 50.0), 20.0, 1.0);
+    }
+
+    #[test]
+    fn humidex_matches_the_table() {
+        // Environment Canada's table, by air temperature and dew point in ºC
+        for (t, dp, expected) in [(30.0, 15.0, 34.0), (30.0, 20.0, 37.0), (30.0, 25.0, 42.0), (35.0, 25.0, 47.0)] {
+            assert_near(humidex(t, dp), expected, 0.6);
+        }
+    }
+
+    #[test]
+    fn comfort_boundaries() {
+        assert_eq!(Comfort::classify(29.9, 5.0), Comfort::Dry);
+        assert_eq!(Comfort::classify(30.0, 5.0), Comfort::Comfortable);
+        assert_eq!(Comfort::classify(60.0, 15.9), Comfort::Comfortable);
+        assert_eq!(Comfort::classify(60.0, 16.0), Comfort::Humid);
+        assert_eq!(Comfort::classify(60.0, 19.9), Comfort::Humid);
+        assert_eq!(Comfort::classify(60.0, 20.0), Comfort::Muggy);
+        // Dry air wins over the dew point
+        assert_eq!(Comfort::classify(20.0, 21.0), Comfort::Dry);
+    }
+
+    #[test]
+    fn comfort_round_trips_as_a_value() {
+        for comfort in [Comfort::Dry, Comfort::Comfortable, Comfort::Humid, Comfort::Muggy] {
+            assert_eq!(Comfort::from_value(comfort.value()), comfort);
+        }
+    }
+
+    #[test]
+    fn derive_needs_temperature_and_humidity() {
+        let mut readings = Readings::default();
+        readings.set(Metric::Temperature, 25.0);
+        readings.set(Metric::Humidity, 60.0);
+        derive(&mut readings);
+        assert_near(readings.get(Metric::DewPoint).unwrap(), 16.7, 0.1);
+        assert_eq!(readings.get(Metric::Comfort), Some(Comfort::Humid.value()));
+
+        readings.remove(Metric::Humidity);
+        derive(&mut readings);
+        assert!(Metric::ALL.iter().filter(|m| m.is_derived()).all(|m| readings.get(*m).is_none()));
+    }
+}
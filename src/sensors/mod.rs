@@ -0,0 +1,597 @@
+use linux_embedded_hal::I2cdev;
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::history::SharedHistory;
+use crate::mailbox::Mailbox;
+use crate::metrics::{Metrics, SharedMetrics};
+use crate::storage::Storage;
+
+mod bh1750;
+mod bme280;
+mod derived;
+mod scd30;
+mod sgp30;
+mod sht3x;
+
+pub use derived::{derive, Comfort};
+pub use scd30::{Scd30Command, Scd30Status};
+
+use bh1750::Bh1750;
+use bme280::Bme280;
+use scd30::Scd30Driver;
+use sgp30::Sgp30;
+use sht3x::Sht3x;
+
+// Re-probe for missing sensors with this backoff, doubled while nothing new shows up
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+// This many bus errors in a row and we drop the driver, it will be re-initialized by probing
+const MAX_CONSECUTIVE_ERRORS: u32 = 5;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Every pressure update restarts the SCD30's measurements, so the barometer is
+// only passed on when it moved this much, and not more often than this
+const PRESSURE_STEP: f32 = 2.0; // hPa
+const PRESSURE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Given by `name` in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Metric {
+    Co2,
+    Temperature,
+    Humidity,
+    Pressure,
+    Tvoc,
+    ECo2,
+    Light,
+    // Derived from temperature and humidity, see derived.rs
+    DewPoint,
+    AbsoluteHumidity,
+    HeatIndex,
+    Humidex,
+    Comfort,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 12] = [
+        Metric::Co2,
+        Metric::Temperature,
+        Metric::Humidity,
+        Metric::Pressure,
+        Metric::Tvoc,
+        Metric::ECo2,
+        Metric::Light,
+        Metric::DewPoint,
+        Metric::AbsoluteHumidity,
+        Metric::HeatIndex,
+        Metric::Humidex,
+        Metric::Comfort,
+    ];
+
+    // Computed from other metrics rather than read from a sensor, so never stored
+    pub fn is_derived(&self) -> bool {
+        matches!(
+            self,
+            Metric::DewPoint
+                | Metric::AbsoluteHumidity
+                | Metric::HeatIndex
+                | Metric::Humidex
+                | Metric::Comfort
+        )
+    }
+
+    // Machine readable name, used in files and exports
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Co2 => "co2",
+            Metric::Temperature => "temperature",
+            Metric::Humidity => "humidity",
+            Metric::Pressure => "pressure",
+            Metric::Tvoc => "tvoc",
+            Metric::ECo2 => "eco2",
+            Metric::Light => "light",
+            Metric::DewPoint => "dew_point",
+            Metric::AbsoluteHumidity => "absolute_humidity",
+            Metric::HeatIndex => "heat_index",
+            Metric::Humidex => "humidex",
+            Metric::Comfort => "comfort",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Metric> {
+        Metric::ALL.iter().find(|m| m.name() == name).copied()
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Metric::Co2 => "Co2",
+            Metric::Temperature => "T",
+            Metric::Humidity => "Hum",
+            Metric::Pressure => "P",
+            Metric::Tvoc => "TVOC",
+            Metric::ECo2 => "eCo2",
+            Metric::Light => "Light",
+            Metric::DewPoint => "Dew",
+            Metric::AbsoluteHumidity => "AH",
+            Metric::HeatIndex => "HI",
+            Metric::Humidex => "Humidex",
+            Metric::Comfort => "Air",
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Metric::Co2 | Metric::ECo2 => "ppm",
+            Metric::Temperature | Metric::DewPoint | Metric::HeatIndex => "ºC",
+            Metric::AbsoluteHumidity => "g/m³",
+            Metric::Humidex | Metric::Comfort => "",
+            Metric::Humidity => "%RH",
+            Metric::Pressure => "hPa",
+            Metric::Tvoc => "ppb",
+            Metric::Light => "lx",
+        }
+    }
+
+    pub fn precision(&self) -> usize {
+        match self {
+            Metric::Temperature | Metric::DewPoint | Metric::AbsoluteHumidity => 1,
+            Metric::HeatIndex => 1,
+            _ => 0,
+        }
+    }
+}
+
+impl TryFrom<String> for Metric {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, String> {
+        Metric::from_name(&name).ok_or_else(|| format!("unknown metric `{}`", name))
+    }
+}
+
+// The latest value of every metric some attached sensor reports
+#[derive(Debug, Clone, Default)]
+pub struct Readings {
+    values: BTreeMap<Metric, f32>,
+}
+
+impl Readings {
+    pub fn get(&self, metric: Metric) -> Option<f32> {
+        self.values.get(&metric).copied()
+    }
+
+    pub fn set(&mut self, metric: Metric, value: f32) {
+        if !value.is_nan() {
+            self.values.insert(metric, value);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    pub fn remove(&mut self, metric: Metric) {
+        self.values.remove(&metric);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Metric, f32)> + '_ {
+        self.values.iter().map(|(m, v)| (*m, *v))
+    }
+}
+
+pub trait Driver {
+    fn name(&self) -> &'static str;
+    fn metrics(&self) -> &'static [Metric];
+    // Adds whatever new values are available to `readings`
+    fn poll(&mut self, readings: &mut Readings) -> Result<(), String>;
+}
+
+pub struct Sensors {
+    pub readings: Mailbox<Readings>,
+    pub history: SharedHistory,
+    pub storage: Option<Storage>,
+    pub scd30_status: Mailbox<Scd30Status>,
+    pub scd30_commands: Receiver<Scd30Command>,
+    pub metrics: SharedMetrics,
+    pub subscribers: Vec<Sender<(DateTime<Utc>, Readings)>>, // get every round's new values
+}
+
+pub fn spawn(config: &Config, sensors: Sensors, cancel: Arc<AtomicBool>) -> JoinHandle<()> {
+    let bus = config.sensors.bus.clone();
+    let scd30 = config.scd30.clone();
+
+    thread::spawn(move || {
+        let mut service = SensorService {
+            bus,
+            scd30: None,
+            pressure: PressureFeed::new(scd30.pressure != 0),
+            scd30_status: Scd30Status::new(scd30),
+            drivers: Vec::new(),
+            latest: Readings::default(),
+            sensors,
+        };
+        service.run(&cancel);
+    })
+}
+
+struct Slot<D: ?Sized> {
+    driver: Box<D>,
+    errors: u32,
+}
+
+impl<D: Driver + ?Sized> Slot<D> {
+    fn new(driver: Box<D>) -> Self {
+        Slot { driver, errors: 0 }
+    }
+
+    // Returns false once the driver has failed too many times in a row
+    fn poll(&mut self, readings: &mut Readings, metrics: &Metrics) -> bool {
+        match self.driver.poll(readings) {
+            Ok(()) => self.errors = 0,
+            Err(e) => {
+                self.errors += 1;
+                metrics.sensor_error(self.driver.name());
+                warn!(
+                    "{} poll error ({}/{}): {}",
+                    self.driver.name(),
+                    self.errors,
+                    MAX_CONSECUTIVE_ERRORS,
+                    e
+                );
+            }
+        }
+
+        self.errors < MAX_CONSECUTIVE_ERRORS
+    }
+}
+
+struct SensorService {
+    bus: String,
+    scd30: Option<Slot<Scd30Driver>>,
+    pressure: PressureFeed,
+    scd30_status: Scd30Status,
+    drivers: Vec<Slot<dyn Driver>>,
+    latest: Readings,
+    sensors: Sensors,
+}
+
+type Probe = fn(I2cdev, u8) -> Result<Box<dyn Driver>, String>;
+
+fn boxed<D: Driver + 'static>(driver: Result<D, String>) -> Result<Box<dyn Driver>, String> {
+    driver.map(|d| Box::new(d) as Box<dyn Driver>)
+}
+
+// Everything besides the SCD30 we know how to talk to, in polling order. When
+// several sensors report the same metric the later one wins, so the dedicated
+// temperature and humidity sensors take precedence over the SCD30.
+const KNOWN_SENSORS: [(&str, &[u8], Probe); 4] = [
+    ("BME280", &[0x76, 0x77], |i2c, addr| boxed(Bme280::probe(i2c, addr))),
+    ("SHT3x", &[0x44, 0x45], |i2c, addr| boxed(Sht3x::probe(i2c, addr))),
+    ("SGP30", &[0x58], |i2c, addr| boxed(Sgp30::probe(i2c, addr))),
+    ("BH1750", &[0x23, 0x5c], |i2c, addr| boxed(Bh1750::probe(i2c, addr))),
+];
+
+impl SensorService {
+    fn run(&mut self, cancel: &AtomicBool) {
+        let mut backoff = RETRY_MIN;
+        let mut next_probe = Instant::now();
+
+        while !cancel.load(Ordering::Relaxed) {
+            if Instant::now() >= next_probe {
+                if self.probe() {
+                    backoff = RETRY_MIN;
+                } else {
+                    backoff = (backoff * 2).min(RETRY_MAX);
+                }
+                next_probe = Instant::now() + backoff;
+            }
+
+            if self.poll() {
+                // Something fell off the bus, look for it again soon
+                backoff = RETRY_MIN;
+                next_probe = Instant::now() + backoff;
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        // Dropping the driver stops continuous measurement
+        self.scd30 = None;
+
+        if let Some(storage) = &mut self.sensors.storage {
+            if let Err(e) = storage.flush() {
+                error!("Could not store readings: {}", e);
+            }
+        }
+    }
+
+    // Looks for sensors that are not attached yet, returns true if any were found
+    fn probe(&mut self) -> bool {
+        let mut found = false;
+
+        if self.scd30.is_none() {
+            match self
+                .open()
+                .and_then(|i2c| Scd30Driver::probe(i2c, &mut self.scd30_status))
+            {
+                Ok(driver) => {
+                    info!("Found SCD30");
+                    self.scd30 = Some(Slot::new(Box::new(driver)));
+                    self.publish_status();
+                    found = true;
+                }
+                Err(e) => debug!("No SCD30: {}", e),
+            }
+        }
+
+        for (name, addresses, probe) in KNOWN_SENSORS {
+            if self.drivers.iter().any(|slot| slot.driver.name() == name) {
+                continue;
+            }
+
+            for address in addresses {
+                let i2c = match self.open() {
+                    Ok(i2c) => i2c,
+                    Err(e) => {
+                        error!("{}", e);
+                        return found;
+                    }
+                };
+
+                if let Ok(driver) = probe(i2c, *address) {
+                    info!("Found {} at {:#04x}", name, address);
+                    self.drivers.push(Slot::new(driver));
+                    found = true;
+                    break;
+                }
+            }
+        }
+
+        // Keep the polling order stable no matter when things were plugged in
+        self.drivers.sort_by_key(|slot| {
+            KNOWN_SENSORS
+                .iter()
+                .position(|(name, _, _)| *name == slot.driver.name())
+        });
+
+        found
+    }
+
+    fn open(&self) -> Result<I2cdev, String> {
+        I2cdev::new(&self.bus).map_err(|e| format!("open {}: {:?}", self.bus, e))
+    }
+
+    // Polls every attached sensor and publishes what they had. Returns true if
+    // some driver was dropped because of errors.
+    fn poll(&mut self) -> bool {
+        let mut readings = Readings::default();
+        let mut lost: Vec<&'static [Metric]> = Vec::new();
+
+        self.handle_scd30_commands();
+
+        if let Some(slot) = &mut self.scd30 {
+            if !slot.poll(&mut readings, &self.sensors.metrics) {
+                lost.push(slot.driver.metrics());
+                self.scd30 = None;
+            }
+        }
+
+        self.drivers.retain_mut(|slot| {
+            let healthy = slot.poll(&mut readings, &self.sensors.metrics);
+            if !healthy {
+                lost.push(slot.driver.metrics());
+            }
+            healthy
+        });
+
+        for metrics in &lost {
+            for metric in metrics.iter() {
+                self.latest.remove(*metric);
+            }
+        }
+
+        for (metric, value) in readings.iter() {
+            self.latest.set(metric, value);
+        }
+        derive(&mut self.latest);
+
+        if !readings.is_empty() {
+            // Whatever was derived from this round's values belongs to it too
+            if readings.get(Metric::Temperature).is_some() || readings.get(Metric::Humidity).is_some() {
+                for metric in Metric::ALL.iter().filter(|m| m.is_derived()) {
+                    if let Some(value) = self.latest.get(*metric) {
+                        readings.set(*metric, value);
+                    }
+                }
+            }
+
+            debug!("Polled {:?}", readings);
+            let now = Utc::now();
+            self.sensors
+                .history
+                .lock()
+                .expect("lock history")
+                .record(now, &readings);
+
+            if let Some(storage) = &mut self.sensors.storage {
+                if let Err(e) = storage.append(now, &readings) {
+                    error!("Could not store readings: {}", e);
+                }
+            }
+
+            self.sensors
+                .subscribers
+                .retain(|tx| tx.send((now, readings.clone())).is_ok());
+        }
+
+        if !readings.is_empty() || !lost.is_empty() {
+            // The screens miss this update, the next one replaces it anyway
+            if let Err(e) = self.sensors.readings.put(self.latest.clone()) {
+                error!("Could not pass on readings: {}", e);
+            }
+        }
+
+        !lost.is_empty()
+    }
+
+    fn handle_scd30_commands(&mut self) {
+        if self.scd30.is_none() {
+            return;
+        }
+
+        let mut commands: Vec<Scd30Command> = self.sensors.scd30_commands.try_iter().collect();
+
+        // Feed the SCD30 live ambient pressure if we have a barometer
+        let pressure = self.latest.get(Metric::Pressure);
+        if let Some(hpa) = self.pressure.update(pressure, self.scd30_status.settings.pressure) {
+            commands.push(Scd30Command::SetPressure(hpa));
+        }
+
+        if commands.is_empty() {
+            return;
+        }
+
+        let slot = self.scd30.as_mut().expect("checked above");
+
+        for cmd in commands {
+            if let Err(e) = slot.driver.command(cmd, &mut self.scd30_status) {
+                self.sensors.metrics.sensor_error(slot.driver.name());
+                error!("Sensor command {:?} failed: {}", cmd, e);
+            }
+        }
+        self.publish_status();
+    }
+
+    fn publish_status(&self) {
+        if let Err(e) = self.sensors.scd30_status.put(self.scd30_status.clone()) {
+            error!("Could not pass on the SCD30 status: {}", e);
+        }
+    }
+}
+
+// Decides when a barometer reading is passed on to the SCD30. Disabled when
+// `scd30.pressure` is 0, which means its pressure compensation is off.
+struct PressureFeed {
+    enabled: bool,
+    attempted: Option<Instant>, // failed writes wait just as long
+}
+
+impl PressureFeed {
+    fn new(enabled: bool) -> Self {
+        PressureFeed {
+            enabled,
+            attempted: None,
+        }
+    }
+
+    // The pressure to set, if `reading` is far enough from what the sensor has
+    fn update(&mut self, reading: Option<f32>, applied: u16) -> Option<u16> {
+        if !self.enabled || matches!(self.attempted, Some(at) if at.elapsed() < PRESSURE_INTERVAL) {
+            return None;
+        }
+
+        let hpa = reading?;
+        if (hpa - applied as f32).abs() < PRESSURE_STEP {
+            return None;
+        }
+
+        self.attempted = Some(Instant::now());
+        Some(hpa.round() as u16)
+    }
+}
+
+// CRC-8 used by the Sensirion sensors (SCD30, SHT3x, SGP30) on every data word
+pub fn sensirion_crc(data: &[u8]) -> u8 {
+    let mut crc = 0xffu8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            if crc & 0x80 != 0 {
+                crc = (crc << 1) ^ 0x31;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+// Splits Sensirion style [msb, lsb, crc]* responses into checked words
+pub fn sensirion_words(buf: &[u8], words: &mut [u16]) -> Result<(), String> {
+    for (chunk, word) in buf.chunks(3).zip(words.iter_mut()) {
+        if sensirion_crc(&chunk[..2]) != chunk[2] {
+            return Err("crc mismatch".to_string());
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pressure_is_fed_on_big_changes_only() {
+        let mut feed = PressureFeed::new(true);
+
+        assert_eq!(feed.update(None, 1004), None);
+        assert_eq!(feed.update(Some(1005.4), 1004), None);
+        assert_eq!(feed.update(Some(1012.6), 1004), Some(1013));
+    }
+
+    #[test]
+    fn pressure_is_fed_at_most_every_interval() {
+        let mut feed = PressureFeed::new(true);
+        assert_eq!(feed.update(Some(990.0), 1004), Some(990));
+
+        // Even if the write failed and the sensor still has the old value
+        assert_eq!(feed.update(Some(990.0), 1004), None);
+
+        feed.attempted = Some(Instant::now() - PRESSURE_INTERVAL);
+        assert_eq!(feed.update(Some(990.0), 1004), Some(990));
+    }
+
+    #[test]
+    fn pressure_is_not_fed_when_disabled() {
+        let mut feed = PressureFeed::new(false);
+        assert_eq!(feed.update(Some(990.0), 0), None);
+    }
+
+    #[test]
+    fn crc_matches_the_datasheet() {
+        // The example from the SHT3x and SCD30 datasheets
+        assert_eq!(sensirion_crc(&[0xbe, 0xef]), 0x92);
+        assert_eq!(sensirion_crc(&[]), 0xff);
+    }
+
+    #[test]
+    fn words_are_checked_and_split() {
+        let mut words = [0u16; 2];
+        sensirion_words(&[0xbe, 0xef, 0x92, 0x80, 0x00, 0xa2], &mut words).expect("valid crcs");
+        assert_eq!(words, [0xbeef, 0x8000]);
+    }
+
+    #[test]
+    fn words_reject_a_bad_crc() {
+        let mut words = [0u16; 2];
+        let result = sensirion_words(&[0xbe, 0xef, 0x92, 0x80, 0x00, 0xa3], &mut words);
+        assert_eq!(result, Err("crc mismatch".to_string()));
+    }
+}
@@ -0,0 +1,126 @@
+use linux_embedded_hal::i2cdev::linux::LinuxI2CError;
+use linux_embedded_hal::Delay;
+use linux_embedded_hal::I2cdev;
+use sensor_scd30::Scd30;
+
+use super::{Driver, Metric, Readings};
+use crate::config::Scd30Config;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Scd30Command {
+    ForceRecalibration(u16), // ppm
+    SetAutoCalibration(bool),
+    SetTemperatureOffset(f32), // ºC
+    SetAltitude(u16),          // m
+    SetPressure(u16),          // hPa
+}
+
+// What has been applied to the sensor, published after every change. Outlives
+// the driver so settings changed at runtime survive a re-initialization.
+#[derive(Debug, Clone)]
+pub struct Scd30Status {
+    pub firmware: Option<u16>,
+    pub settings: Scd30Config,
+    pub last_calibration: Option<u16>,
+}
+
+impl Scd30Status {
+    pub fn new(settings: Scd30Config) -> Self {
+        Scd30Status {
+            firmware: None,
+            settings,
+            last_calibration: None,
+        }
+    }
+}
+
+pub struct Scd30Driver {
+    scd: Scd30<I2cdev, Delay, LinuxI2CError>,
+}
+
+impl Scd30Driver {
+    pub fn probe(i2c: I2cdev, status: &mut Scd30Status) -> Result<Self, String> {
+        let settings = &status.settings;
+        let mut scd = Scd30::new(i2c, Delay {}).map_err(|e| format!("init: {:?}", e))?;
+
+        scd.set_measurement_interval(settings.measurement_interval)
+            .map_err(|e| format!("set interval: {:?}", e))?;
+        if let Some(enabled) = settings.auto_calibration {
+            scd.set_afc(enabled)
+                .map_err(|e| format!("set auto calibration: {:?}", e))?;
+        }
+        if let Some(offset) = settings.temperature_offset {
+            scd.set_temp_offset(offset)
+                .map_err(|e| format!("set temperature offset: {:?}", e))?;
+        }
+        if let Some(altitude) = settings.altitude {
+            scd.set_alt_offset(altitude)
+                .map_err(|e| format!("set altitude: {:?}", e))?;
+        }
+        scd.start_continuous(settings.pressure)
+            .map_err(|e| format!("start: {:?}", e))?;
+
+        status.firmware = scd.firmware_version().ok();
+
+        Ok(Scd30Driver { scd })
+    }
+
+    pub fn command(&mut self, cmd: Scd30Command, status: &mut Scd30Status) -> Result<(), String> {
+        let settings = &mut status.settings;
+
+        match cmd {
+            Scd30Command::ForceRecalibration(ppm) => self.scd.set_frc(ppm).map(|_| {
+                status.last_calibration = Some(ppm);
+                settings.calibration_reference = ppm;
+            }),
+            Scd30Command::SetAutoCalibration(enabled) => self
+                .scd
+                .set_afc(enabled)
+                .map(|_| settings.auto_calibration = Some(enabled)),
+            Scd30Command::SetTemperatureOffset(offset) => self
+                .scd
+                .set_temp_offset(offset)
+                .map(|_| settings.temperature_offset = Some(offset)),
+            Scd30Command::SetAltitude(altitude) => self
+                .scd
+                .set_alt_offset(altitude)
+                .map(|_| settings.altitude = Some(altitude)),
+            // Ambient pressure is passed along when (re)starting measurements
+            Scd30Command::SetPressure(hpa) => self
+                .scd
+                .start_continuous(hpa)
+                .map(|_| settings.pressure = hpa),
+        }
+        .map_err(|e| format!("{:?}", e))
+    }
+}
+
+impl Driver for Scd30Driver {
+    fn name(&self) -> &'static str {
+        "SCD30"
+    }
+
+    fn metrics(&self) -> &'static [Metric] {
+        &[Metric::Co2, Metric::Temperature, Metric::Humidity]
+    }
+
+    fn poll(&mut self, readings: &mut Readings) -> Result<(), String> {
+        if !self.scd.data_ready().map_err(|e| format!("{:?}", e))? {
+            return Ok(());
+        }
+
+        let m = self.scd.read_data().map_err(|e| format!("{:?}", e))?;
+        readings.set(Metric::Co2, m.co2);
+        readings.set(Metric::Temperature, m.temp);
+        readings.set(Metric::Humidity, m.rh);
+
+        Ok(())
+    }
+}
+
+impl Drop for Scd30Driver {
+    fn drop(&mut self) {
+        // Best effort, if we're dropped because of bus errors this will fail too
+        let _ = self.scd.stop_continuous();
+    }
+}
@@ -0,0 +1,162 @@
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use std::fmt::Debug;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{sensirion_words, Driver, Metric, Readings};
+
+const GET_FEATURE_SET: [u8; 2] = [0x20, 0x2f];
+const INIT_AIR_QUALITY: [u8; 2] = [0x20, 0x03];
+const MEASURE_AIR_QUALITY: [u8; 2] = [0x20, 0x08];
+const MEASURE_TIME: Duration = Duration::from_millis(12);
+
+// The first readings after init are fixed at 400 ppm / 0 ppb
+const WARMUP: Duration = Duration::from_secs(15);
+
+// Gas sensor, its baseline algorithm wants a measurement every second which
+// matches our polling interval
+pub struct Sgp30<I2C> {
+    i2c: I2C,
+    address: u8,
+    started: Instant,
+}
+
+impl<I2C, E> Sgp30<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    pub fn probe(mut i2c: I2C, address: u8) -> Result<Self, String> {
+        let mut buf = [0u8; 3];
+        i2c.write_read(address, &GET_FEATURE_SET, &mut buf)
+            .map_err(|e| format!("get feature set: {:?}", e))?;
+
+        let mut feature_set = [0u16];
+        sensirion_words(&buf, &mut feature_set)?;
+        if feature_set[0] >> 12 != 0 {
+            return Err(format!("unexpected product type {:#06x}", feature_set[0]));
+        }
+
+        i2c.write(address, &INIT_AIR_QUALITY)
+            .map_err(|e| format!("init: {:?}", e))?;
+
+        Ok(Sgp30 {
+            i2c,
+            address,
+            started: Instant::now(),
+        })
+    }
+}
+
+impl<I2C, E> Driver for Sgp30<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    fn name(&self) -> &'static str {
+        "SGP30"
+    }
+
+    fn metrics(&self) -> &'static [Metric] {
+        &[Metric::ECo2, Metric::Tvoc]
+    }
+
+    fn poll(&mut self, readings: &mut Readings) -> Result<(), String> {
+        self.i2c
+            .write(self.address, &MEASURE_AIR_QUALITY)
+            .map_err(|e| format!("measure: {:?}", e))?;
+        thread::sleep(MEASURE_TIME);
+
+        let mut buf = [0u8; 6];
+        self.i2c
+            .read(self.address, &mut buf)
+            .map_err(|e| format!("read: {:?}", e))?;
+
+        let mut words = [0u16; 2];
+        sensirion_words(&buf, &mut words)?;
+
+        if self.started.elapsed() >= WARMUP {
+            readings.set(Metric::ECo2, words[0] as f32);
+            readings.set(Metric::Tvoc, words[1] as f32);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::sensirion_crc;
+    use embedded_hal_mock::i2c::{Mock, Transaction};
+
+    const ADDRESS: u8 = 0x58;
+
+    fn word(value: u16) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        vec![bytes[0], bytes[1], sensirion_crc(&bytes)]
+    }
+
+    fn probe_transactions() -> Vec<Transaction> {
+        vec![
+            Transaction::write_read(ADDRESS, GET_FEATURE_SET.to_vec(), word(0x0022)),
+            Transaction::write(ADDRESS, INIT_AIR_QUALITY.to_vec()),
+        ]
+    }
+
+    fn measure_transactions(eco2: u16, tvoc: u16) -> Vec<Transaction> {
+        let mut measurement = word(eco2);
+        measurement.extend(word(tvoc));
+        vec![
+            Transaction::write(ADDRESS, MEASURE_AIR_QUALITY.to_vec()),
+            Transaction::read(ADDRESS, measurement),
+        ]
+    }
+
+    #[test]
+    fn probes_and_measures_after_warmup() {
+        let mut expectations = probe_transactions();
+        expectations.extend(measure_transactions(400, 0));
+        expectations.extend(measure_transactions(612, 35));
+        let mut bus = Mock::new(&expectations);
+
+        let mut sensor = Sgp30::probe(bus.clone(), ADDRESS).expect("probe");
+
+        // The fixed warmup values are not readings
+        let mut readings = Readings::default();
+        sensor.poll(&mut readings).expect("poll");
+        assert!(readings.is_empty());
+
+        sensor.started = Instant::now() - WARMUP;
+        sensor.poll(&mut readings).expect("poll");
+        assert_eq!(readings.get(Metric::ECo2), Some(612.0));
+        assert_eq!(readings.get(Metric::Tvoc), Some(35.0));
+        bus.done();
+    }
+
+    #[test]
+    fn rejects_other_products() {
+        let mut bus = Mock::new(&[Transaction::write_read(
+            ADDRESS,
+            GET_FEATURE_SET.to_vec(),
+            word(0x1022),
+        )]);
+
+        assert!(Sgp30::probe(bus.clone(), ADDRESS).is_err());
+        bus.done();
+    }
+
+    #[test]
+    fn poll_rejects_bad_crc() {
+        let mut expectations = probe_transactions();
+        expectations.push(Transaction::write(ADDRESS, MEASURE_AIR_QUALITY.to_vec()));
+        expectations.push(Transaction::read(ADDRESS, vec![0x02, 0x64, 0x00, 0x00, 0x23, 0x00]));
+        let mut bus = Mock::new(&expectations);
+
+        let mut sensor = Sgp30::probe(bus.clone(), ADDRESS).expect("probe");
+        sensor.started = Instant::now() - WARMUP;
+        let mut readings = Readings::default();
+        assert!(sensor.poll(&mut readings).is_err());
+        assert!(readings.is_empty());
+        bus.done();
+    }
+}
@@ -0,0 +1,127 @@
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use std::fmt::Debug;
+use std::thread;
+use std::time::Duration;
+
+use super::{sensirion_words, Driver, Metric, Readings};
+
+const READ_STATUS: [u8; 2] = [0xf3, 0x2d];
+// Single shot, high repeatability, no clock stretching
+const MEASURE: [u8; 2] = [0x24, 0x00];
+const MEASURE_TIME: Duration = Duration::from_millis(16);
+
+pub struct Sht3x<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C, E> Sht3x<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    pub fn probe(mut i2c: I2C, address: u8) -> Result<Self, String> {
+        let mut buf = [0u8; 3];
+        i2c.write_read(address, &READ_STATUS, &mut buf)
+            .map_err(|e| format!("read status: {:?}", e))?;
+        sensirion_words(&buf, &mut [0])?;
+
+        Ok(Sht3x { i2c, address })
+    }
+}
+
+impl<I2C, E> Driver for Sht3x<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    fn name(&self) -> &'static str {
+        "SHT3x"
+    }
+
+    fn metrics(&self) -> &'static [Metric] {
+        &[Metric::Temperature, Metric::Humidity]
+    }
+
+    fn poll(&mut self, readings: &mut Readings) -> Result<(), String> {
+        self.i2c
+            .write(self.address, &MEASURE)
+            .map_err(|e| format!("measure: {:?}", e))?;
+        thread::sleep(MEASURE_TIME);
+
+        let mut buf = [0u8; 6];
+        self.i2c
+            .read(self.address, &mut buf)
+            .map_err(|e| format!("read: {:?}", e))?;
+
+        let mut words = [0u16; 2];
+        sensirion_words(&buf, &mut words)?;
+
+        readings.set(Metric::Temperature, -45.0 + 175.0 * words[0] as f32 / 65535.0);
+        readings.set(Metric::Humidity, 100.0 * words[1] as f32 / 65535.0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::sensirion_crc;
+    use embedded_hal_mock::i2c::{Mock, Transaction};
+
+    const ADDRESS: u8 = 0x44;
+
+    fn word(value: u16) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        vec![bytes[0], bytes[1], sensirion_crc(&bytes)]
+    }
+
+    #[test]
+    fn probes_and_measures() {
+        let mut measurement = word(0x6666); // 25 ºC
+        measurement.extend(word(0x8000)); // 50 %RH
+        let mut bus = Mock::new(&[
+            Transaction::write_read(ADDRESS, READ_STATUS.to_vec(), word(0x8010)),
+            Transaction::write(ADDRESS, MEASURE.to_vec()),
+            Transaction::read(ADDRESS, measurement),
+        ]);
+
+        let mut sensor = Sht3x::probe(bus.clone(), ADDRESS).expect("probe");
+        let mut readings = Readings::default();
+        sensor.poll(&mut readings).expect("poll");
+
+        assert!((readings.get(Metric::Temperature).unwrap() - 25.0).abs() < 0.01);
+        assert!((readings.get(Metric::Humidity).unwrap() - 50.0).abs() < 0.01);
+        bus.done();
+    }
+
+    #[test]
+    fn probe_rejects_bad_crc() {
+        // Something else at the address, answering with garbage
+        let mut bus = Mock::new(&[Transaction::write_read(
+            ADDRESS,
+            READ_STATUS.to_vec(),
+            vec![0x80, 0x10, 0x00],
+        )]);
+
+        assert!(Sht3x::probe(bus.clone(), ADDRESS).is_err());
+        bus.done();
+    }
+
+    #[test]
+    fn poll_rejects_bad_crc() {
+        let mut measurement = word(0x6666);
+        measurement.extend([0x80, 0x00, 0x00]);
+        let mut bus = Mock::new(&[
+            Transaction::write_read(ADDRESS, READ_STATUS.to_vec(), word(0x8010)),
+            Transaction::write(ADDRESS, MEASURE.to_vec()),
+            Transaction::read(ADDRESS, measurement),
+        ]);
+
+        let mut sensor = Sht3x::probe(bus.clone(), ADDRESS).expect("probe");
+        let mut readings = Readings::default();
+        assert!(sensor.poll(&mut readings).is_err());
+        assert!(readings.is_empty());
+        bus.done();
+    }
+}
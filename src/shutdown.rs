@@ -0,0 +1,210 @@
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::Rgb888,
+    prelude::*,
+    text::{Alignment, Text},
+};
+use log::{debug, error, info, warn};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::config::{GoodbyeStyle, ShutdownConfig};
+use crate::screens::{Canvas, Screen};
+
+// How often to look whether the workers are done
+const POLL: Duration = Duration::from_millis(20);
+
+// Every worker thread, so they can be waited for before exiting. They all
+// watch the same term flag the main loop does, that is what tells them to stop.
+// Cloned into whatever starts threads of its own, e.g. one per connection.
+#[derive(Clone)]
+pub struct Workers {
+    handles: Arc<Mutex<Handles>>,
+}
+
+struct Handles {
+    running: Vec<(&'static str, JoinHandle<()>)>,
+    failed: usize,
+}
+
+impl Workers {
+    pub fn new() -> Self {
+        Workers {
+            handles: Arc::new(Mutex::new(Handles {
+                running: Vec::new(),
+                failed: 0,
+            })),
+        }
+    }
+
+    pub fn add(&self, name: &'static str, handle: JoinHandle<()>) {
+        let mut handles = self.handles.lock().expect("lock workers");
+        // Short lived ones would pile up otherwise
+        for name in handles.reap() {
+            debug!("{} stopped", name);
+        }
+        handles.running.push((name, handle));
+    }
+
+    // Waits up to `timeout` for all of them, returns how many panicked or are still running
+    pub fn join(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let mut handles = self.handles.lock().expect("lock workers");
+            for name in handles.reap() {
+                info!("{} stopped", name);
+            }
+
+            if handles.running.is_empty() {
+                return handles.failed;
+            }
+            if Instant::now() >= deadline {
+                for (name, _) in &handles.running {
+                    error!("{} did not stop within {:?}, leaving it", name, timeout);
+                }
+                return handles.failed + handles.running.len();
+            }
+
+            drop(handles);
+            thread::sleep(POLL);
+        }
+    }
+}
+
+impl Handles {
+    // Joins the ones that are done, returns the names of those that did not crash
+    fn reap(&mut self) -> Vec<&'static str> {
+        let (finished, running): (Vec<_>, Vec<_>) =
+            mem::take(&mut self.running).into_iter().partition(|(_, h)| h.is_finished());
+        self.running = running;
+
+        let mut stopped = Vec::new();
+        for (name, handle) in finished {
+            match handle.join() {
+                Ok(()) => stopped.push(name),
+                Err(_) => {
+                    error!("{} had crashed", name);
+                    self.failed += 1;
+                }
+            }
+        }
+        stopped
+    }
+}
+// What the panel shows while the workers stop, before it goes dark
+pub enum Goodbye {
+    Fade(Duration),
+    Text(String, Duration),
+    None,
+}
+
+impl Goodbye {
+    pub fn new(config: &ShutdownConfig) -> Self {
+        let duration = Duration::from_millis(config.duration);
+        match config.goodbye {
+            GoodbyeStyle::Fade => Goodbye::Fade(duration),
+            GoodbyeStyle::Text => Goodbye::Text(config.text.clone(), duration),
+            GoodbyeStyle::None => Goodbye::None,
+        }
+    }
+
+    pub fn show(&self, canvas: &mut Canvas, screen: &mut dyn Screen) {
+        match self {
+            // Keeps the screen animating while it dims
+            Goodbye::Fade(duration) => {
+                let brightness = canvas.brightness() as f32;
+                let start = Instant::now();
+                while start.elapsed() < *duration {
+                    let left = 1.0 - start.elapsed().as_secs_f32() / duration.as_secs_f32();
+                    canvas.set_brightness((brightness * left) as u8);
+                    canvas.clear();
+                    if let Err(e) = screen.draw(canvas) {
+                        warn!("{} screen failed while fading out: {}", screen.name(), e);
+                        break;
+                    }
+                    canvas.swap();
+                }
+            }
+            Goodbye::Text(text, duration) => {
+                let style = MonoTextStyle::new(&FONT_6X10, Rgb888::WHITE);
+                canvas.clear();
+                let drawn = Text::with_alignment(text, Point::new(32, 20), style, Alignment::Center).draw(canvas);
+                if let Err(e) = drawn {
+                    error!("Could not draw the goodbye: {}", e);
+                }
+                canvas.swap();
+                thread::sleep(*duration);
+            }
+            Goodbye::None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // Runs until the flag is set, like the real workers
+    fn worker(cancel: &Arc<AtomicBool>) -> JoinHandle<()> {
+        let cancel = Arc::clone(cancel);
+        thread::spawn(move || {
+            while !cancel.load(Ordering::Relaxed) {
+                thread::sleep(POLL);
+            }
+        })
+    }
+
+    #[test]
+    fn stopped_workers_are_not_counted() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let workers = Workers::new();
+        workers.add("worker", worker(&cancel));
+        workers.add("quick", thread::spawn(|| {}));
+
+        cancel.store(true, Ordering::Relaxed);
+        assert_eq!(workers.join(Duration::from_secs(5)), 0);
+    }
+
+    #[test]
+    fn panicked_workers_are_counted() {
+        let workers = Workers::new();
+        workers.add("panics", thread::spawn(|| panic!("on purpose")));
+        workers.add("quick", thread::spawn(|| {}));
+
+        assert_eq!(workers.join(Duration::from_secs(5)), 1);
+    }
+
+    #[test]
+    fn panics_reaped_early_are_still_counted() {
+        let workers = Workers::new();
+        let panics = thread::spawn(|| panic!("on purpose"));
+        while !panics.is_finished() {
+            thread::sleep(POLL);
+        }
+        workers.add("panics", panics);
+
+        // Reaps the one that panicked
+        workers.add("quick", thread::spawn(|| {}));
+        assert_eq!(workers.join(Duration::from_secs(5)), 1);
+    }
+
+    #[test]
+    fn workers_still_running_are_counted() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let workers = Workers::new();
+        workers.add("stuck", worker(&cancel));
+        workers.add("panics", thread::spawn(|| panic!("on purpose")));
+
+        let start = Instant::now();
+        assert_eq!(workers.join(Duration::from_millis(100)), 2);
+        assert!(start.elapsed() < Duration::from_secs(1), "{:?}", start.elapsed());
+
+        // Once it stops it is no longer counted
+        cancel.store(true, Ordering::Relaxed);
+        assert_eq!(workers.join(Duration::from_secs(5)), 1);
+    }
+}
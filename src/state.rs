@@ -0,0 +1,284 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::config::StorageConfig;
+use crate::screens::Screen;
+
+// No need to compare the state with what is saved every frame
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Saved once it has stopped changing for this long, so turning the knob
+// through a dozen hues is one write and not a dozen
+const DEBOUNCE: Duration = Duration::from_secs(3);
+
+// What the display looked like, restored on the next start
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct State {
+    pub screen: Option<String>,
+    pub brightness: Option<u8>,
+    pub screens: BTreeMap<String, Value>, // by screen name, whatever each one saves
+}
+
+impl State {
+    pub fn capture(screens: &[&mut dyn Screen], active: usize, brightness: u8) -> Self {
+        State {
+            screen: Some(screens[active].name().to_string()),
+            brightness: Some(brightness),
+            screens: screens
+                .iter()
+                .filter_map(|screen| screen.save().map(|state| (screen.name().to_string(), state)))
+                .collect(),
+        }
+    }
+
+    // Hands every screen what it saved, returns the screen that was active
+    pub fn restore(&self, screens: &mut [&mut dyn Screen]) -> Option<usize> {
+        for screen in screens.iter_mut() {
+            if let Some(state) = self.screens.get(screen.name()) {
+                screen.restore(state);
+            }
+        }
+
+        let active = self.screen.as_ref()?;
+        screens.iter().position(|screen| screen.name() == active)
+    }
+}
+
+// `state.json` in the storage directory
+pub struct StateFile {
+    path: PathBuf,
+    saved: State,
+    pending: Option<(State, Instant)>, // changed, waiting for it to settle
+    checked: Instant,
+}
+
+impl StateFile {
+    // Also returns the saved state, the default one if there is none or it is unreadable
+    pub fn open(config: &StorageConfig) -> (Self, State) {
+        let path = PathBuf::from(&config.dir).join("state.json");
+
+        let saved = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable state in {}: {}", path.display(), e);
+                State::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => State::default(),
+            Err(e) => {
+                warn!("Could not read state from {}: {}", path.display(), e);
+                State::default()
+            }
+        };
+
+        if saved != State::default() {
+            info!("Restoring state from {}", path.display());
+        }
+
+        let file = StateFile {
+            path,
+            saved: saved.clone(),
+            pending: None,
+            checked: Instant::now(),
+        };
+        (file, saved)
+    }
+
+    // Called every frame, `current` is only asked for now and then
+    pub fn update(&mut self, current: impl FnOnce() -> State) {
+        if self.checked.elapsed() < CHECK_INTERVAL {
+            return;
+        }
+        self.checked = Instant::now();
+
+        let current = current();
+        if current == self.saved {
+            self.pending = None;
+            return;
+        }
+
+        match &self.pending {
+            Some((pending, since)) if *pending == current => {
+                if since.elapsed() >= DEBOUNCE {
+                    self.save(current);
+                }
+            }
+            _ => self.pending = Some((current, Instant::now())),
+        }
+    }
+
+    // Saves right away if anything changed, for shutting down
+    pub fn flush(&mut self, current: State) {
+        if current != self.saved {
+            self.save(current);
+        }
+    }
+
+    fn save(&mut self, state: State) {
+        match self.write(&state) {
+            Ok(()) => {
+                self.saved = state;
+                self.pending = None;
+            }
+            // Tried again on the next check
+            Err(e) => warn!("Could not save state to {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn write(&self, state: &State) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // Write next to it and rename, so a crash mid-write keeps the old state
+        let tmp = self.path.with_extension("json.tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(serde_json::to_string_pretty(state)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Result;
+    use crate::screens::Canvas;
+    use serde_json::json;
+
+    struct Dial {
+        name: &'static str,
+        value: Option<u64>,
+    }
+
+    impl Screen for Dial {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+        fn left(&mut self) {}
+        fn right(&mut self) {}
+        fn click(&mut self) {}
+        fn draw(&mut self, _canvas: &mut Canvas) -> Result<()> {
+            Ok(())
+        }
+
+        fn save(&self) -> Option<Value> {
+            self.value.map(|value| json!({ "value": value }))
+        }
+
+        fn restore(&mut self, state: &Value) {
+            self.value = state["value"].as_u64();
+        }
+    }
+
+    fn state(brightness: u8) -> State {
+        State {
+            screen: Some("dial".to_string()),
+            brightness: Some(brightness),
+            screens: BTreeMap::new(),
+        }
+    }
+
+    // As if `elapsed` had passed since the last check and change
+    fn wait(file: &mut StateFile, elapsed: Duration) {
+        file.checked -= elapsed;
+        if let Some((_, since)) = &mut file.pending {
+            *since -= elapsed;
+        }
+    }
+
+    fn open(dir: &tempfile::TempDir) -> (StateFile, State) {
+        StateFile::open(&StorageConfig {
+            dir: dir.path().to_str().expect("utf-8 path").to_string(),
+            ..StorageConfig::default()
+        })
+    }
+
+    #[test]
+    fn saved_once_it_settles() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let (mut file, saved) = open(&dir);
+        assert_eq!(saved, State::default());
+
+        // Not even looked at until the next check
+        file.update(|| panic!("checked too soon"));
+
+        wait(&mut file, CHECK_INTERVAL);
+        file.update(|| state(40));
+        wait(&mut file, CHECK_INTERVAL);
+        file.update(|| state(40));
+        assert!(!file.path.exists());
+
+        // A change starts the wait over
+        wait(&mut file, DEBOUNCE);
+        file.update(|| state(60));
+        wait(&mut file, CHECK_INTERVAL);
+        file.update(|| state(60));
+        assert!(!file.path.exists());
+
+        wait(&mut file, DEBOUNCE);
+        file.update(|| state(60));
+        assert!(file.pending.is_none());
+        assert_eq!(open(&dir).1, state(60));
+    }
+
+    #[test]
+    fn going_back_is_not_a_change() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let (mut file, _) = open(&dir);
+
+        wait(&mut file, CHECK_INTERVAL);
+        file.update(|| state(40));
+        wait(&mut file, DEBOUNCE);
+        file.update(State::default);
+        assert!(file.pending.is_none());
+        assert!(!file.path.exists());
+    }
+
+    #[test]
+    fn flushed_right_away() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let (mut file, _) = open(&dir);
+
+        file.flush(state(20));
+        assert_eq!(open(&dir).1, state(20));
+    }
+
+    #[test]
+    fn unreadable_state_is_ignored() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        fs::write(dir.path().join("state.json"), "{ not json").expect("write");
+        assert_eq!(open(&dir).1, State::default());
+    }
+
+    #[test]
+    fn capture_and_restore() {
+        let mut first = Dial { name: "first", value: None };
+        let mut second = Dial { name: "second", value: Some(7) };
+        let screens: [&mut dyn Screen; 2] = [&mut first, &mut second];
+
+        let state = State::capture(&screens, 1, 80);
+        assert_eq!(state.screen.as_deref(), Some("second"));
+        assert_eq!(state.brightness, Some(80));
+        assert_eq!(state.screens.len(), 1);
+
+        let mut first = Dial { name: "first", value: None };
+        let mut second = Dial { name: "second", value: None };
+        let mut screens: [&mut dyn Screen; 2] = [&mut first, &mut second];
+        assert_eq!(state.restore(&mut screens), Some(1));
+        assert_eq!(second.value, Some(7));
+
+        // A screen that is gone since is not an error
+        let gone = State {
+            screen: Some("maze".to_string()),
+            ..state
+        };
+        let mut screens: [&mut dyn Screen; 1] = [&mut first];
+        assert_eq!(gone.restore(&mut screens), None);
+    }
+}
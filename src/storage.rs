@@ -0,0 +1,308 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use log::{error, info};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use crate::config::StorageConfig;
+use crate::history::{History, SharedHistory};
+use crate::sensors::{derive, Metric, Readings};
+
+// How far back we reload on startup, the longest the history keeps anything
+const RELOAD_DAYS: i64 = 30;
+
+// Rows are written out this often rather than one by one, to spare the SD card.
+// A power cut loses at most this much.
+const FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// Readings are appended to one CSV file per (UTC) day, `readings-YYYY-MM-DD.csv`,
+// with a row per polling round and a column per metric
+pub struct Storage {
+    dir: PathBuf,
+    retention_days: u32,
+    file: Option<(NaiveDate, BufWriter<File>)>,
+    flushed: Instant,
+}
+
+impl Storage {
+    pub fn open(config: &StorageConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+
+        Ok(Storage {
+            dir: PathBuf::from(&config.dir),
+            retention_days: config.retention_days,
+            file: None,
+            flushed: Instant::now(),
+        })
+    }
+
+    pub fn append(&mut self, at: DateTime<Utc>, readings: &Readings) -> io::Result<()> {
+        let date = at.date_naive();
+
+        if !matches!(&self.file, Some((d, _)) if *d == date) {
+            // Finish the previous day before moving on
+            self.flush()?;
+            self.file = Some((date, self.create(date)?));
+            self.prune()?;
+        }
+
+        let (_, file) = self.file.as_mut().expect("file was just opened");
+        write!(file, "{}", at.to_rfc3339())?;
+        for metric in stored_metrics() {
+            match readings.get(metric) {
+                Some(value) => write!(file, ",{}", value)?,
+                None => write!(file, ",")?,
+            }
+        }
+        writeln!(file)?;
+
+        if self.flushed.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.flushed = Instant::now();
+        match &mut self.file {
+            Some((_, file)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn create(&self, date: NaiveDate) -> io::Result<BufWriter<File>> {
+        let path = self.path(date);
+        let is_new = !path.exists();
+
+        let mut file = BufWriter::new(OpenOptions::new().create(true).append(true).open(&path)?);
+        if is_new {
+            let names: Vec<&str> = stored_metrics().map(|m| m.name()).collect();
+            writeln!(file, "time,{}", names.join(","))?;
+        }
+
+        Ok(file)
+    }
+
+    fn path(&self, date: NaiveDate) -> PathBuf {
+        self.dir.join(format!("readings-{}.csv", date.format("%Y-%m-%d")))
+    }
+
+    // Removes files that are past the retention, 0 keeps everything
+    fn prune(&self) -> io::Result<()> {
+        if self.retention_days == 0 {
+            return Ok(());
+        }
+
+        let oldest = Utc::now().date_naive() - Duration::days(self.retention_days as i64);
+        for (date, path) in self.files()? {
+            if date < oldest {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // All data files sorted by date
+    fn files(&self) -> io::Result<Vec<(NaiveDate, PathBuf)>> {
+        let mut files = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let date = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_prefix("readings-"))
+                .and_then(|n| n.strip_suffix(".csv"))
+                .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+
+            if let Some(date) = date {
+                files.push((date, path));
+            }
+        }
+
+        files.sort();
+        Ok(files)
+    }
+
+    // Calls `callback` for every stored row in [from, to), in order
+    pub fn read_range(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        mut callback: impl FnMut(DateTime<Utc>, &Readings),
+    ) -> io::Result<()> {
+        for (date, path) in self.files()? {
+            if date < from.date_naive() || date > to.date_naive() {
+                continue;
+            }
+
+            read_file(&path, |at, readings| {
+                if at >= from && at < to {
+                    callback(at, readings);
+                }
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // Everything stored before `until`, as far back as the history goes. A day
+    // at a time, so it can be cancelled; None if it was.
+    pub fn load(&self, until: DateTime<Utc>, cancel: &AtomicBool) -> io::Result<Option<History>> {
+        let mut history = History::new();
+        let mut derived = Readings::default();
+
+        let mut from = until - Duration::days(RELOAD_DAYS);
+        while from < until {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+
+            let to = (from + Duration::days(1)).min(until);
+            self.read_range(from, to, |at, readings| {
+                derived.clone_from(readings);
+                derive(&mut derived);
+                history.record(at, &derived)
+            })?;
+            from = to;
+        }
+
+        Ok(Some(history))
+    }
+}
+
+// Reloads the stored readings into `history` in the background, a month of
+// them takes a while to parse and the display should not wait for it. Readings
+// recorded meanwhile are kept, the old ones go in front of them.
+pub fn reload(config: &StorageConfig, history: SharedHistory, cancel: Arc<AtomicBool>) -> JoinHandle<()> {
+    let config = config.clone();
+    let until = Utc::now();
+
+    thread::spawn(move || {
+        let started = Instant::now();
+        let loaded = Storage::open(&config).and_then(|storage| storage.load(until, &cancel));
+
+        match loaded {
+            Ok(Some(loaded)) => {
+                history.lock().expect("lock history").prepend(loaded);
+                info!("Loaded stored readings in {:.1?}", started.elapsed());
+            }
+            Ok(None) => {}
+            Err(e) => error!("Could not load stored readings: {}", e),
+        }
+    })
+}
+
+// Derived metrics are recomputed when loading instead
+pub fn stored_metrics() -> impl Iterator<Item = Metric> {
+    Metric::ALL.into_iter().filter(|m| !m.is_derived())
+}
+
+fn read_file(path: &Path, mut callback: impl FnMut(DateTime<Utc>, &Readings)) -> io::Result<()> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+
+    // Go by the header, so files written before a metric existed still load
+    let header = match lines.next() {
+        Some(line) => line?,
+        None => return Ok(()),
+    };
+    let columns: Vec<Option<Metric>> = header.split(',').skip(1).map(Metric::from_name).collect();
+
+    let mut readings = Readings::default();
+    for line in lines {
+        let line = line?;
+        let mut fields = line.split(',');
+
+        let at = match fields.next().map(DateTime::parse_from_rfc3339) {
+            Some(Ok(at)) => at.with_timezone(&Utc),
+            _ => continue, // half written row
+        };
+
+        readings.clear();
+        for (metric, field) in columns.iter().zip(fields) {
+            if let (Some(metric), Ok(value)) = (metric, field.parse()) {
+                readings.set(*metric, value);
+            }
+        }
+
+        callback(at, &readings);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &tempfile::TempDir) -> Storage {
+        let config = StorageConfig {
+            enabled: true,
+            dir: dir.path().to_str().expect("utf-8 path").to_string(),
+            retention_days: 0,
+        };
+        Storage::open(&config).expect("open storage")
+    }
+
+    fn readings() -> Readings {
+        let mut readings = Readings::default();
+        readings.set(Metric::Co2, 612.0);
+        readings.set(Metric::Temperature, 21.5);
+        readings.set(Metric::Humidity, 40.0);
+        readings
+    }
+
+    #[test]
+    fn rows_load_back_with_derived_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = open(&dir);
+        storage.append(Utc::now() - Duration::minutes(5), &readings()).unwrap();
+        storage.flush().unwrap();
+
+        let history = storage
+            .load(Utc::now(), &AtomicBool::new(false))
+            .unwrap()
+            .expect("not cancelled");
+        assert_eq!(history.latest(Metric::Co2).map(|a| a.mean), Some(612.0));
+        assert!(history.latest(Metric::DewPoint).is_some());
+    }
+
+    #[test]
+    fn rows_after_until_are_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = open(&dir);
+        let until = Utc::now() - Duration::minutes(10);
+        storage.append(Utc::now() - Duration::minutes(5), &readings()).unwrap();
+        storage.flush().unwrap();
+
+        let history = storage.load(until, &AtomicBool::new(false)).unwrap().unwrap();
+        assert!(history.latest(Metric::Co2).is_none());
+    }
+
+    #[test]
+    fn rows_are_written_out_in_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = open(&dir);
+        let at = Utc::now();
+        storage.append(at, &readings()).unwrap();
+
+        let path = storage.path(at.date_naive());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 0);
+
+        storage.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn load_can_be_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir);
+
+        assert!(storage.load(Utc::now(), &AtomicBool::new(true)).unwrap().is_none());
+    }
+}
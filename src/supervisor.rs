@@ -0,0 +1,228 @@
+use embedded_graphics::{
+    mono_font::{ascii::FONT_4X6, MonoTextStyle},
+    pixelcolor::Rgb888,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::Text,
+};
+use log::{error, info};
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::screens::{Canvas, Screen};
+
+// A screen failing this many frames in a row is taken out of the rotation
+const MAX_FAILURES: u32 = 3;
+
+// How long the error glyph stays up after a failure
+const GLYPH_TIME: Duration = Duration::from_secs(3);
+
+struct Health {
+    failures: u32, // in a row, a good frame starts over
+    disabled: bool,
+}
+
+// Keeps one misbehaving screen from taking the whole display down. A failed
+// draw is logged and the screen reset; if it keeps failing it is disabled and
+// the display moves on to the next screen.
+pub struct Supervisor {
+    health: Vec<Health>,
+    failed_at: Option<Instant>,
+}
+
+impl Supervisor {
+    pub fn new(screens: usize) -> Self {
+        Supervisor {
+            health: (0..screens)
+                .map(|_| Health {
+                    failures: 0,
+                    disabled: false,
+                })
+                .collect(),
+            failed_at: None,
+        }
+    }
+
+    pub fn draw(&mut self, index: usize, screen: &mut dyn Screen, canvas: &mut Canvas) {
+        let health = &mut self.health[index];
+
+        match screen.draw(canvas) {
+            Ok(()) => health.failures = 0,
+            Err(e) => {
+                health.failures += 1;
+                self.failed_at = Some(Instant::now());
+
+                if health.failures >= MAX_FAILURES {
+                    error!("{} screen failed {} times, disabling it: {}", screen.name(), health.failures, e);
+                    health.disabled = true;
+                } else {
+                    error!("{} screen failed, resetting it: {}", screen.name(), e);
+                    screen.reset();
+                }
+            }
+        }
+
+        if matches!(self.failed_at, Some(at) if at.elapsed() < GLYPH_TIME) {
+            if let Err(e) = draw_glyph(canvas) {
+                error!("Could not draw the error glyph: {}", e);
+            }
+        }
+    }
+
+    // The screen to show instead of `index`, skipping disabled ones in the
+    // direction the user was going
+    pub fn enabled(&self, index: usize, forward: bool) -> usize {
+        let count = self.health.len();
+        let step = if forward { 1 } else { count - 1 };
+
+        let mut candidate = index;
+        for _ in 0..count {
+            if !self.health[candidate].disabled {
+                return candidate;
+            }
+            candidate = (candidate + step) % count;
+        }
+
+        // Everything is broken, nothing better to show
+        index
+    }
+
+    // Gives a disabled screen another chance when asked for by name
+    pub fn enable(&mut self, index: usize, screen: &mut dyn Screen) {
+        let health = &mut self.health[index];
+        if health.disabled {
+            info!("Enabling {} screen again", screen.name());
+            screen.reset();
+            health.disabled = false;
+            health.failures = 0;
+        }
+    }
+}
+
+// A red `!` in the bottom right corner
+fn draw_glyph(canvas: &mut Canvas) -> Result<()> {
+    let (width, height) = canvas.canvas_size();
+    Rectangle::new(Point::new(width - 5, height - 8), Size::new(5, 8))
+        .into_styled(PrimitiveStyle::with_fill(Rgb888::new(200, 0, 0)))
+        .draw(canvas)?;
+    Text::new("!", Point::new(width - 4, height - 2), MonoTextStyle::new(&FONT_4X6, Rgb888::WHITE))
+        .draw(canvas)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::preview::Frame;
+    use crate::screens::{HEIGHT, WIDTH};
+    use std::collections::VecDeque;
+
+    // Fails or not as scripted, then draws fine
+    struct Flaky {
+        script: VecDeque<bool>,
+        resets: usize,
+    }
+
+    impl Flaky {
+        fn new(script: &[bool]) -> Self {
+            Flaky {
+                script: script.iter().copied().collect(),
+                resets: 0,
+            }
+        }
+    }
+
+    impl Screen for Flaky {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+        fn left(&mut self) {}
+        fn right(&mut self) {}
+        fn click(&mut self) {}
+
+        fn draw(&mut self, _canvas: &mut Canvas) -> Result<()> {
+            match self.script.pop_front() {
+                Some(false) => Err(Error::Maze("scripted".to_string())),
+                _ => Ok(()),
+            }
+        }
+
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+    }
+
+    fn canvas() -> Canvas {
+        Canvas::new(None, Frame::shared(WIDTH, HEIGHT))
+    }
+
+    #[test]
+    fn disabled_after_failing_in_a_row() {
+        let mut supervisor = Supervisor::new(3);
+        let mut screen = Flaky::new(&[false, false, false]);
+        let mut canvas = canvas();
+
+        for _ in 0..2 {
+            supervisor.draw(1, &mut screen, &mut canvas);
+            assert_eq!(supervisor.enabled(1, true), 1);
+        }
+        supervisor.draw(1, &mut screen, &mut canvas);
+        assert_eq!(supervisor.enabled(1, true), 2);
+        assert_eq!(supervisor.enabled(1, false), 0);
+
+        // Reset after each failure but the last
+        assert_eq!(screen.resets, 2);
+    }
+
+    #[test]
+    fn a_good_frame_starts_over() {
+        let mut supervisor = Supervisor::new(1);
+        let mut screen = Flaky::new(&[false, false, true, false, false, true]);
+        let mut canvas = canvas();
+
+        for _ in 0..6 {
+            supervisor.draw(0, &mut screen, &mut canvas);
+        }
+        assert!(!supervisor.health[0].disabled);
+        assert_eq!(supervisor.health[0].failures, 0);
+    }
+
+    #[test]
+    fn skips_disabled_screens() {
+        let mut supervisor = Supervisor::new(4);
+        supervisor.health[1].disabled = true;
+        supervisor.health[2].disabled = true;
+
+        assert_eq!(supervisor.enabled(0, true), 0);
+        assert_eq!(supervisor.enabled(1, true), 3);
+        assert_eq!(supervisor.enabled(2, false), 0);
+
+        // Nothing better to show when everything is broken
+        for health in &mut supervisor.health {
+            health.disabled = true;
+        }
+        assert_eq!(supervisor.enabled(2, true), 2);
+    }
+
+    #[test]
+    fn enabled_again_by_name() {
+        let mut supervisor = Supervisor::new(2);
+        let mut screen = Flaky::new(&[false, false, false]);
+        let mut canvas = canvas();
+
+        for _ in 0..3 {
+            supervisor.draw(0, &mut screen, &mut canvas);
+        }
+        assert_eq!(supervisor.enabled(0, true), 1);
+
+        supervisor.enable(0, &mut screen);
+        assert_eq!(supervisor.enabled(0, true), 0);
+        assert_eq!(supervisor.health[0].failures, 0);
+        assert_eq!(screen.resets, 3);
+
+        // Enabling one that is fine does nothing
+        supervisor.enable(1, &mut screen);
+        assert_eq!(screen.resets, 3);
+    }
+}
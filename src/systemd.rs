@@ -0,0 +1,63 @@
+use log::{debug, warn};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+// Sends a state like "READY=1" to systemd, see sd_notify(3). Does nothing
+// when not started by systemd, or started without Type=notify.
+pub fn notify(state: &str) {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+
+    if let Err(e) = sent {
+        warn!("Could not notify systemd of {}: {}", state, e);
+    }
+}
+
+// Pings the systemd watchdog, if WatchdogSec= is set for the service. Meant to
+// be called from the main loop every frame, so a hung frame gets us restarted.
+pub struct Watchdog {
+    interval: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        let interval = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            // Ping twice per timeout, as sd_watchdog_enabled(3) recommends
+            .map(|usec: u64| Duration::from_micros(usec / 2));
+
+        if let Some(interval) = interval {
+            debug!("Pinging the systemd watchdog every {:?}", interval);
+        }
+
+        Watchdog {
+            interval,
+            last_ping: None,
+        }
+    }
+
+    pub fn ping(&mut self) {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        if !matches!(self.last_ping, Some(at) if at.elapsed() < interval) {
+            notify("WATCHDOG=1");
+            self.last_ping = Some(Instant::now());
+        }
+    }
+}